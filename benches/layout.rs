@@ -0,0 +1,52 @@
+// Producer/consumer throughput of an spsc channel, with one thread on
+// each side. Run it once per layout and compare the numbers:
+//
+//   cargo bench --bench layout --no-default-features --features std  # unpadded
+//   cargo bench --bench layout                                       # padded seqno and head
+//   cargo bench --bench layout --features padded-slots               # padded slot flags too
+//
+// It needs at least two cores, otherwise it only measures the scheduler.
+extern crate lossyq;
+
+use lossyq::spsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ITEMS : usize = 10_000_000;
+const RUNS  : usize = 5;
+
+// returns the elapsed time, the items received and the items lost
+fn run(size: usize) -> (Duration, usize, usize) {
+  let (mut tx, mut rx) = spsc::channel::<usize>(size);
+  let started_at = Instant::now();
+  let producer = thread::spawn(move|| {
+    for i in 0..ITEMS {
+      tx.put(|v| *v = Some(i));
+    }
+  });
+
+  let mut received = 0;
+  while let Ok(it) = rx.try_iter() {
+    received += it.count();
+  }
+  let elapsed = started_at.elapsed();
+  producer.join().unwrap();
+  (elapsed, received, rx.dropped())
+}
+
+fn main() {
+  let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+  if cores < 2 {
+    println!("warning: {} core available, the results are meaningless", cores);
+  }
+  println!("{:>8} {:>12} {:>12} {:>8}", "size", "Mitems/s", "received", "lost");
+  for size in [16, 256, 4096] {
+    let mut best = (Duration::MAX, 0, 0);
+    for _i in 0..RUNS {
+      let r = run(size);
+      if r.0 < best.0 { best = r; }
+    }
+    let rate = ITEMS as f64 / best.0.as_secs_f64() / 1e6;
+    println!("{:>8} {:>12.2} {:>12} {:>8}", size, rate, best.1, best.2);
+  }
+}
@@ -0,0 +1,173 @@
+// Criterion suite for the spsc protocol: put latency, iter batch cost,
+// noloss::pour overhead and the end-to-end latency of an item. Run it with
+//
+//   cargo bench --bench throughput
+//
+// and add a filter, like `-- put/`, to run a single group.
+extern crate criterion;
+extern crate lossyq;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+use criterion::{criterion_group, criterion_main};
+use lossyq::spsc::{self, Receiver, Sender};
+use lossyq::spsc::noloss::{pour, Overflow};
+use std::hint::black_box;
+use std::marker::PhantomData;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SIZES : [usize; 3] = [16, 256, 4096];
+
+// the payloads under test. update() changes an item in place, the way a
+// writer reuses the overwritten item it gets back from put()
+trait Payload : Send + 'static {
+  const NAME : &'static str;
+  fn make(i: usize) -> Self;
+  fn update(&mut self, i: usize);
+}
+
+impl Payload for i32 {
+  const NAME : &'static str = "i32";
+  fn make(i: usize) -> i32 { i as i32 }
+  fn update(&mut self, i: usize) { *self = i as i32; }
+}
+
+impl Payload for String {
+  const NAME : &'static str = "string";
+  fn make(i: usize) -> String { format!("item {:>16}", i) }
+  fn update(&mut self, i: usize) {
+    self.clear();
+    self.push_str("item ");
+    self.push_str(&i.to_string());
+  }
+}
+
+impl Payload for [u8; 4096] {
+  const NAME : &'static str = "4k_array";
+  fn make(i: usize) -> [u8; 4096] { [i as u8; 4096] }
+  fn update(&mut self, i: usize) { self[0] = i as u8; }
+}
+
+#[inline(always)]
+fn put_one<T: Payload>(tx: &mut Sender<T>, i: usize) {
+  tx.put(|v| match *v {
+    Some(ref mut x) => x.update(i),
+    None => *v = Some(T::make(i)),
+  });
+}
+
+fn filled<T: Payload>(size: usize) -> (Sender<T>, Receiver<T>) {
+  let (mut tx, rx) = spsc::channel(size);
+  for i in 0..size { put_one(&mut tx, i); }
+  (tx, rx)
+}
+
+// a single put with nobody reading, so the ring keeps overwriting itself
+fn put<T: Payload>(c: &mut Criterion) {
+  let mut group = c.benchmark_group(format!("put/{}", T::NAME));
+  group.throughput(Throughput::Elements(1));
+  for &size in SIZES.iter() {
+    group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+      let (mut tx, _rx) = filled::<T>(size);
+      let mut i = 0;
+      b.iter(|| { put_one(&mut tx, i); i += 1; });
+    });
+  }
+  group.finish();
+}
+
+// swapping a full ring out and draining it
+fn iter<T: Payload>(c: &mut Criterion) {
+  let mut group = c.benchmark_group(format!("iter/{}", T::NAME));
+  for &size in SIZES.iter() {
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+      b.iter_batched_ref(
+        || filled::<T>(size),
+        |ch| {
+          for item in ch.1.iter() { black_box(item); }
+        },
+        BatchSize::LargeInput);
+    });
+  }
+  group.finish();
+}
+
+struct Discard<T>(PhantomData<T>);
+
+impl<T: Send> Overflow for Discard<T> {
+  type Input = T;
+  fn overflow(&mut self, val : &mut Option<T>) {
+    black_box(val.take());
+  }
+}
+
+// pour into a ring nobody reads, every call after the first lap overflows
+fn pour_overflow<T: Payload>(c: &mut Criterion) {
+  let mut group = c.benchmark_group(format!("pour/{}", T::NAME));
+  group.throughput(Throughput::Elements(1));
+  for &size in SIZES.iter() {
+    group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+      let (mut tx, _rx) = spsc::channel::<T>(size);
+      let mut overflow = Discard(PhantomData);
+      let mut i = 0;
+      b.iter(|| {
+        let mut value = Some(T::make(i));
+        pour(&mut value, &mut tx, &mut overflow);
+        i += 1;
+      });
+    });
+  }
+  group.finish();
+}
+
+// round trip of an item to an echo thread and back, both sides parked in
+// wait_iter() between items. half of it is the one way latency.
+fn round_trip<T: Payload>(c: &mut Criterion) {
+  let mut group = c.benchmark_group(format!("round_trip/{}", T::NAME));
+  for &size in SIZES.iter() {
+    group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+      b.iter_custom(|iters| {
+        let (mut ping_tx, mut ping_rx) = spsc::channel::<T>(size);
+        let (mut pong_tx, mut pong_rx) = spsc::channel::<T>(size);
+        let echo = thread::spawn(move|| {
+          while !ping_rx.is_disconnected() {
+            for item in ping_rx.wait_iter_timeout(Duration::from_millis(10)) {
+              let mut item = Some(item);
+              pong_tx.put(|v| *v = item.take());
+            }
+          }
+        });
+
+        let started_at = Instant::now();
+        for i in 0..iters {
+          put_one(&mut ping_tx, i as usize);
+          while pong_rx.wait_iter().count() == 0 { }
+        }
+        let elapsed = started_at.elapsed();
+        drop(ping_tx);
+        echo.join().unwrap();
+        elapsed
+      });
+    });
+  }
+  group.finish();
+}
+
+fn payloads(c: &mut Criterion) {
+  put::<i32>(c);
+  put::<String>(c);
+  put::<[u8; 4096]>(c);
+  iter::<i32>(c);
+  iter::<String>(c);
+  iter::<[u8; 4096]>(c);
+  pour_overflow::<i32>(c);
+  pour_overflow::<String>(c);
+  pour_overflow::<[u8; 4096]>(c);
+  round_trip::<i32>(c);
+  round_trip::<String>(c);
+  round_trip::<[u8; 4096]>(c);
+}
+
+criterion_group!(benches, payloads);
+criterion_main!(benches);
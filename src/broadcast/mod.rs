@@ -0,0 +1,341 @@
+use std::cell::UnsafeCell;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::cb::{IterRange, MIN_GEN_BITS, pos_bits};
+
+// The publisher and the subscribers count the ring slots in laps and
+// positions next to their seqnos, so the slots follow each other across
+// the seqno wraparound. A slot is packed like a flag: (lap << pos_bits) | pos.
+struct SharedBuffer<T> {
+  seqno       : AtomicUsize,              // the ID of the next item to be published
+  data        : Vec<UnsafeCell<Option<T>>>, // (2*n)+1 preallocated elements
+  pins        : Vec<AtomicUsize>,         // number of subscribers reading each element
+  size        : usize,                    // n
+  pos_bits    : usize,                    // low bits of the flags holding a position
+  buffer      : Vec<AtomicUsize>,         // (generation << pos_bits) | position
+}
+
+pub struct Publisher<T> {
+  inner       : Arc<SharedBuffer<T>>,
+  spare       : Vec<usize>,               // positions the writer owns
+  seqno_priv  : usize,
+  write_slot  : usize,                    // lap and position of seqno_priv
+  published   : usize,                    // items put so far, saturating
+}
+
+unsafe impl<T: Send> Send for Publisher<T> { }
+
+pub struct Subscriber<T> {
+  inner       : Arc<SharedBuffer<T>>,
+  max_read    : usize,                    // the next seqno this subscriber expects
+  read_slot   : usize,                    // and its lap and position
+  dropped     : usize,                    // seqnos this subscriber never got to see
+}
+
+unsafe impl<T: Send + Sync> Send for Subscriber<T> { }
+
+pub struct BroadcastIterator<'a, T: 'a> {
+  inner       : &'a SharedBuffer<T>,
+  dropped     : &'a mut usize,
+  start       : usize,
+  end         : usize,
+  slot        : usize,                    // lap and position of start
+  skipped     : usize,
+}
+
+pub fn channel<T: Send + Sync>(size : usize) -> (Publisher<T>, Subscriber<T>) {
+  from_buffer(SharedBuffer::with_seqno(size, 0))
+}
+
+fn from_buffer<T: Send + Sync>(buffer : SharedBuffer<T>) -> (Publisher<T>, Subscriber<T>) {
+  let a = Arc::new(buffer);
+  let seqno = a.seqno.load(Ordering::Acquire);
+  (Publisher::new(a.clone(), seqno), Subscriber::new(a, seqno, 0))
+}
+
+impl <T> SharedBuffer<T> {
+  // starts the sequence numbers at seqno, tests use it to force a wraparound
+  fn with_seqno(size : usize, seqno : usize) -> SharedBuffer<T> {
+
+    let mut size = size;
+
+    // size cannot be zero, silently set to one
+    if size == 0 { size = 1; }
+
+    // the flags are tagged like the ones of the spsc ring
+    let bits = 8 * mem::size_of::<usize>();
+    let pos_bits = pos_bits(size);
+    if bits - pos_bits < MIN_GEN_BITS {
+      panic!("size: {} is too large, it leaves less than {} generation bits", size, MIN_GEN_BITS);
+    }
+
+    let mut ret = SharedBuffer {
+      seqno   : AtomicUsize::new(seqno),
+      data    : Vec::with_capacity(2*size+1),
+      pins    : Vec::with_capacity(2*size+1),
+      size,
+      pos_bits,
+      buffer  : Vec::with_capacity(size),
+    };
+
+    ret.data.push(UnsafeCell::new(None));
+    ret.pins.push(AtomicUsize::new(0));
+
+    for i in 0..size {
+      ret.buffer.push(AtomicUsize::new(ret.flag(0, i)));
+      // 2*size
+      ret.data.push(UnsafeCell::new(None));
+      ret.data.push(UnsafeCell::new(None));
+      ret.pins.push(AtomicUsize::new(0));
+      ret.pins.push(AtomicUsize::new(0));
+    }
+
+    ret
+  }
+
+  #[inline(always)]
+  fn flag(&self, gen: usize, pos: usize) -> usize {
+    (gen << self.pos_bits) | pos
+  }
+
+  #[inline(always)]
+  fn pos_of(&self, flag: usize) -> usize {
+    flag & ((1 << self.pos_bits) - 1)
+  }
+
+  // the generation of a slot is its lap around the ring, the initial
+  // flags carry generation 0 so they look older than the first lap
+  #[inline(always)]
+  fn gen_of(&self, slot: usize) -> usize {
+    (slot >> self.pos_bits).wrapping_add(1)
+  }
+
+  // True if the flag belongs to an earlier lap than gen. The generations
+  // are truncated to the high bits of the flags, a subscriber has to stall
+  // for half of their range in laps before a newer one looks older.
+  #[inline(always)]
+  fn is_older(&self, flag: usize, gen: usize) -> bool {
+    let diff : usize = self.flag(gen, 0).wrapping_sub(self.flag(flag >> self.pos_bits, 0));
+    diff != 0 && diff <= 1 << (usize::BITS - 1)
+  }
+
+  // the slot n seqnos after the given one
+  #[inline(always)]
+  fn advance(&self, slot: usize, n: usize) -> usize {
+    let lap : usize = (slot >> self.pos_bits).wrapping_add(n / self.size);
+    let pos : usize = self.pos_of(slot) + n % self.size;
+    if pos < self.size {
+      self.flag(lap, pos)
+    } else {
+      self.flag(lap.wrapping_add(1), pos - self.size)
+    }
+  }
+
+  #[inline(always)]
+  fn step(&self, slot: usize) -> usize {
+    if self.pos_of(slot) + 1 == self.size {
+      self.flag((slot >> self.pos_bits).wrapping_add(1), 0)
+    } else {
+      slot + 1
+    }
+  }
+
+  // the slot n seqnos before the given one, n is at most size
+  #[inline(always)]
+  fn retreat(&self, slot: usize, n: usize) -> usize {
+    let pos : usize = self.pos_of(slot);
+    if pos >= n {
+      slot - n
+    } else {
+      self.flag((slot >> self.pos_bits).wrapping_sub(1), pos + self.size - n)
+    }
+  }
+
+  // Clones the item of a slot unless it has been overwritten. The pin
+  // keeps the writer away from the element while it is being read. A slot
+  // the writer has not got to yet reads as empty.
+  fn read(&self, slot: usize) -> Option<Option<T>>
+    where T: Clone
+  {
+    let gen = self.gen_of(slot);
+    let flag = &self.buffer[self.pos_of(slot)];
+
+    let old_flag : usize = flag.load(Ordering::SeqCst);
+    if old_flag >> self.pos_bits != self.flag(gen, 0) >> self.pos_bits {
+      return if self.is_older(old_flag, gen) { Some(None) } else { None };
+    }
+
+    let pos = self.pos_of(old_flag);
+    self.pins[pos].fetch_add(1, Ordering::SeqCst);
+    let ret = if flag.load(Ordering::SeqCst) == old_flag {
+      Some(unsafe { (*self.data[pos].get()).clone() })
+    } else {
+      None
+    };
+    self.pins[pos].fetch_sub(1, Ordering::SeqCst);
+    ret
+  }
+}
+
+impl<T: Send + Sync> Publisher<T> {
+  // the first item gets seqno, lap 0 and position 0
+  fn new(inner: Arc<SharedBuffer<T>>, seqno: usize) -> Publisher<T> {
+    let size = inner.size;
+    Publisher {
+      inner,
+      spare       : (size..2*size+1).collect(),
+      seqno_priv  : seqno,
+      write_slot  : 0,
+      published   : 0,
+    }
+  }
+
+  // The setter receives whatever an earlier item left in the element.
+  // Every subscriber pins at most one element at a time, so with no more
+  // than n subscribers there is always a free one. Otherwise the item is
+  // not written and the subscribers will see the seqno as lost.
+  #[inline(always)]
+  pub fn put<F>(&mut self, setter: F) -> usize
+      where F : FnMut(&mut Option<T>) {
+    let mut setter = setter;
+    let shared = &*self.inner;
+
+    let slot    = self.write_slot;
+    let gen     = shared.gen_of(slot);
+
+    // find an element that no subscriber is reading
+    let free = self.spare.iter().position(|p| shared.pins[*p].load(Ordering::SeqCst) == 0);
+    if let Some(i) = free {
+      let pos = self.spare[i];
+      unsafe { setter(&mut *shared.data[pos].get()); }
+      let new_flag : usize = shared.flag(gen, pos);
+      let old_flag : usize = shared.buffer[shared.pos_of(slot)].swap(new_flag, Ordering::SeqCst);
+      self.spare[i] = shared.pos_of(old_flag);
+    }
+
+    self.seqno_priv = self.seqno_priv.wrapping_add(1);
+    self.write_slot = shared.step(slot);
+    self.published = self.published.saturating_add(1);
+    shared.seqno.swap(self.seqno_priv, Ordering::AcqRel)
+  }
+
+  pub fn subscribe(&self) -> Subscriber<T> {
+    Subscriber::new(self.inner.clone(), self.seqno_priv, self.write_slot)
+      .rewind(self.published)
+  }
+
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.inner.seqno.load(Ordering::Acquire)
+  }
+}
+
+impl<T: Send + Sync> Subscriber<T> {
+  fn new(inner: Arc<SharedBuffer<T>>, max_read: usize, read_slot: usize) -> Subscriber<T> {
+    Subscriber { inner, max_read, read_slot, dropped: 0, }
+  }
+
+  // a new subscriber sees the items still in the buffer, out of published
+  fn rewind(mut self, published: usize) -> Subscriber<T> {
+    let back = published.min(self.inner.size);
+    self.max_read = self.max_read.wrapping_sub(back);
+    self.read_slot = self.inner.retreat(self.read_slot, back);
+    self
+  }
+
+  // Returns clones of the items published since the previous call. The
+  // whole range counts as read, no matter how far the iterator is consumed.
+  #[inline(always)]
+  pub fn iter(&mut self) -> BroadcastIterator<'_, T> {
+    let shared = &*self.inner;
+    let seqno : usize = shared.seqno.load(Ordering::Acquire);
+    let unread : usize = seqno.wrapping_sub(self.max_read);
+    let skipped : usize = unread.saturating_sub(shared.size);
+    let start : usize = self.max_read.wrapping_add(skipped);
+    let slot : usize = shared.advance(self.read_slot, skipped);
+    self.max_read = seqno;
+    self.read_slot = shared.advance(slot, unread - skipped);
+    self.dropped += skipped;
+
+    BroadcastIterator {
+      inner   : shared,
+      dropped : &mut self.dropped,
+      start,
+      end     : seqno,
+      slot,
+      skipped,
+    }
+  }
+
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.inner.seqno.load(Ordering::Acquire)
+  }
+
+  // total number of items this subscriber missed so far
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.dropped
+  }
+}
+
+impl<T> Clone for Subscriber<T> {
+  // the clone starts from the same position, but reads independently
+  fn clone(&self) -> Subscriber<T> {
+    Subscriber {
+      inner     : self.inner.clone(),
+      max_read  : self.max_read,
+      read_slot : self.read_slot,
+      dropped   : self.dropped,
+    }
+  }
+}
+
+impl <'a, T: 'a + Clone> Iterator for BroadcastIterator<'a, T> {
+  type Item = T;
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<T> {
+    while self.start != self.end {
+      let slot = self.slot;
+      self.start = self.start.wrapping_add(1);
+      self.slot = self.inner.step(slot);
+      match self.inner.read(slot) {
+        Some(Some(v)) => return Some(v),
+        Some(None) => {},
+        None => {
+          // overwritten while we were iterating
+          self.skipped += 1;
+          *self.dropped += 1;
+        }
+      }
+    }
+    None
+  }
+}
+
+impl <'a, T: 'a> IterRange for BroadcastIterator<'a, T> {
+
+  #[inline(always)]
+  fn get_range(&self) -> (usize, usize) {
+    (self.start, self.end)
+  }
+
+  #[inline(always)]
+  fn next_id(&self) -> Option<usize> {
+    if self.start != self.end {
+      Some(self.start)
+    } else {
+      None
+    }
+  }
+
+  #[inline(always)]
+  fn skipped(&self) -> usize {
+    self.skipped
+  }
+}
+
+#[cfg(test)]
+pub mod tests;
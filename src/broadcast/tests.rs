@@ -0,0 +1,148 @@
+use broadcast;
+use std::thread;
+use cb::IterRange;
+
+#[test]
+fn each_subscriber_reads() {
+  let (mut tx, mut rx1) = broadcast::channel(4);
+  let mut rx2 = tx.subscribe();
+  tx.put(|v| *v = Some(1));
+  tx.put(|v| *v = Some(2));
+  tx.put(|v| *v = Some(3));
+  assert_eq!(rx1.iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+  assert_eq!(rx1.iter().count(), 0);
+  {
+    let i = rx2.iter();
+    assert_eq!(i.get_range(), (0, 3));
+    assert_eq!(i.collect::<Vec<i32>>(), vec![1, 2, 3]);
+  }
+  assert_eq!(rx2.iter().count(), 0);
+}
+
+#[test]
+fn late_subscriber() {
+  // a new subscriber sees what is still in the buffer
+  let (mut tx, _rx) = broadcast::channel(3);
+  for i in 0..5 {
+    tx.put(|v| *v = Some(i));
+  }
+  let mut rx = tx.subscribe();
+  {
+    let i = rx.iter();
+    assert_eq!(i.skipped(), 0);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![2, 3, 4]);
+  }
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn independent_loss() {
+  let (mut tx, mut fast) = broadcast::channel(3);
+  let mut slow = fast.clone();
+  for i in 0..3 {
+    tx.put(|v| *v = Some(i));
+  }
+  assert_eq!(fast.iter().count(), 3);
+  for i in 3..8 {
+    tx.put(|v| *v = Some(i));
+  }
+  {
+    let i = fast.iter();
+    assert_eq!(i.skipped(), 2);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![5, 6, 7]);
+  }
+  {
+    let i = slow.iter();
+    assert_eq!(i.skipped(), 5);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![5, 6, 7]);
+  }
+  assert_eq!(fast.dropped(), 2);
+  assert_eq!(slow.dropped(), 5);
+}
+
+#[test]
+fn overwritten_while_iterating() {
+  // the iterator reads lazily, so it may find items overwritten
+  let (mut tx, mut rx) = broadcast::channel(2);
+  tx.put(|v| *v = Some(String::from("a")));
+  tx.put(|v| *v = Some(String::from("b")));
+  let mut i = rx.iter();
+  assert_eq!(i.next(), Some(String::from("a")));
+  tx.put(|v| *v = Some(String::from("c")));
+  tx.put(|v| *v = Some(String::from("d")));
+  assert_eq!(i.next(), None);
+  assert_eq!(i.skipped(), 1);
+  assert_eq!(rx.dropped(), 1);
+}
+
+#[test]
+fn lapped_while_iterating() {
+  // the publisher laps an open iterator many times, the items it finds
+  // in the slots belong to later seqnos and must not pass for the old ones
+  let (mut tx, mut rx) = broadcast::channel::<usize>(2);
+  tx.put(|v| *v = Some(0));
+  tx.put(|v| *v = Some(1));
+  {
+    let mut i = rx.iter();
+    assert_eq!(i.next(), Some(0));
+    for n in 2..34 {
+      tx.put(|v| *v = Some(n));
+    }
+    assert_eq!(i.next(), None);
+    assert_eq!(i.skipped(), 1);
+  }
+  assert_eq!(rx.dropped(), 1);
+  assert_eq!(rx.iter().collect::<Vec<usize>>(), vec![32, 33]);
+}
+
+#[test]
+fn seqno_turnover() {
+  let (mut tx, mut rx) = broadcast::from_buffer(broadcast::SharedBuffer::with_seqno(3, usize::MAX-1));
+  for i in 0..3 {
+    tx.put(|v| *v = Some(i));
+  }
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 1, 2]);
+  for i in 3..7 {
+    tx.put(|v| *v = Some(i));
+  }
+  let mut late = tx.subscribe();
+  {
+    let i = rx.iter();
+    assert_eq!(i.get_range(), (2, 5));
+    assert_eq!(i.skipped(), 1);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![4, 5, 6]);
+  }
+  assert_eq!(late.iter().collect::<Vec<i32>>(), vec![4, 5, 6]);
+  assert_eq!(late.dropped(), 0);
+}
+
+#[test]
+fn concurrent_subscribers() {
+  let (mut tx, rx) = broadcast::channel::<Vec<usize>>(8);
+  let mut threads = Vec::new();
+  for _t in 0..3 {
+    let mut rx = rx.clone();
+    threads.push(thread::spawn(move|| {
+      let mut last = None;
+      let mut recvd = 0usize;
+      while last != Some(9_999) {
+        for v in rx.iter() {
+          // every element carries the same value
+          assert!(v.iter().all(|x| *x == v[0]));
+          if let Some(prev) = last {
+            assert!(prev < v[0]);
+          }
+          last = Some(v[0]);
+          recvd += 1;
+        }
+      }
+      assert_eq!(recvd + rx.dropped(), 10_000);
+    }));
+  }
+  for i in 0..10_000usize {
+    tx.put(|v| *v = Some(vec![i; 16]));
+  }
+  for t in threads {
+    t.join().unwrap();
+  }
+}
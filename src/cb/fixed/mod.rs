@@ -0,0 +1,260 @@
+use core::marker::PhantomData;
+use core::slice;
+use sync::{AtomicUsize, CachePadded, UnsafeCell};
+use super::{Ring, Storage, Flag, PartialIterator, INITIAL_GEN, MIN_GEN_BITS, pos_bits};
+use super::Writer as RingWriter;
+use super::Reader as RingReader;
+pub use super::CircularBufferIterator;
+
+// A CircularBuffer with its N elements inline, with no heap allocation at
+// all. It runs the same protocol as the Vec backed one, only the storage
+// of the ring differs, so new() can build it at compile time.
+//
+// split() hands out a Writer and a Reader that work from different
+// threads, or from an interrupt handler and the main loop. Neither of
+// them waits for the other, so the target needs atomic swap and
+// compare-exchange on usize.
+pub struct CircularBuffer<T, const N: usize> {
+  ring        : Ring<T, Inline<T, N>>,
+  writer      : RingWriter,
+  reader      : RingReader<[usize; N]>,
+}
+
+// the writing side of a split CircularBuffer
+pub struct Writer<'a, T: 'a, const N: usize> {
+  ring        : &'a Ring<T, Inline<T, N>>,
+  writer      : &'a mut RingWriter,
+}
+
+// the reading side of a split CircularBuffer
+pub struct Reader<'a, T: 'a, const N: usize> {
+  ring        : &'a Ring<T, Inline<T, N>>,
+  reader      : &'a mut RingReader<[usize; N]>,
+}
+
+struct Inline<T, const N: usize> {
+  data        : Elements<UnsafeCell<Option<T>>, N>,
+  stamps      : Elements<UnsafeCell<usize>, N>,
+  buffer      : [Flag; N],
+}
+
+// (2*N)+1 elements back to back, the ring sees them as a slice
+#[repr(C)]
+struct Elements<E, const N: usize> {
+  pairs       : [[E; 2]; N],
+  last        : E,
+}
+
+#[cfg(feature = "padded-slots")]
+const fn new_flag(flag: usize) -> Flag {
+  CachePadded::new(AtomicUsize::new(flag))
+}
+
+#[cfg(not(feature = "padded-slots"))]
+const fn new_flag(flag: usize) -> Flag {
+  AtomicUsize::new(flag)
+}
+
+impl <T, const N: usize> CircularBuffer<T, N> {
+  // referenced by new(), so a zero sized or oversized buffer fails to compile
+  const LAYOUT : () = {
+    assert!(N > 0, "the size of a CircularBuffer must not be zero");
+    assert!(usize::BITS as usize - pos_bits(N) >= MIN_GEN_BITS,
+      "the size of a CircularBuffer leaves too few generation bits");
+  };
+
+  pub const fn new() -> CircularBuffer<T, N> {
+    #[allow(clippy::let_unit_value)]
+    let _ = Self::LAYOUT;
+    let pos_bits = pos_bits(N);
+
+    // the same initial state as CircularBuffer::with_seqno(N, 0)
+    let mut buffer = [const { new_flag(0) }; N];
+    let mut read_priv = [0; N];
+    let mut i = 0;
+    while i < N {
+      buffer[i] = new_flag((INITIAL_GEN << pos_bits) | (1+i));
+      read_priv[i] = 1+N+i;
+      i += 1;
+    }
+
+    let slots = Inline {
+      data        : Elements {
+        pairs : [const { [UnsafeCell::new(None), UnsafeCell::new(None)] }; N],
+        last  : UnsafeCell::new(None),
+      },
+      // no item may look like it was already read
+      stamps      : Elements {
+        pairs : [const { [UnsafeCell::new(usize::MAX), UnsafeCell::new(usize::MAX)] }; N],
+        last  : UnsafeCell::new(usize::MAX),
+      },
+      buffer,
+    };
+
+    CircularBuffer {
+      ring        : Ring {
+        seqno       : CachePadded::new(AtomicUsize::new(0)),
+        head        : CachePadded::new(AtomicUsize::new(0)),
+        read_seqno  : CachePadded::new(AtomicUsize::new(0)),
+        slots,
+        #[cfg(feature = "std")]
+        timestamped : false,
+        size        : N,
+        pos_bits,
+        _items      : PhantomData,
+      },
+      writer      : RingWriter::new(0),
+      reader      : RingReader::new(read_priv, 0),
+    }
+  }
+
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.ring.seqno()
+  }
+
+  // number of seqnos written since the last iter() call
+  #[inline(always)]
+  pub fn unread(&self) -> usize {
+    self.reader.unread(&self.ring)
+  }
+
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.reader.dropped()
+  }
+
+  // The setter receives the writer's element, which may still hold an
+  // unread item the previous put pushed out of the ring. Returns the seqno
+  // of the item.
+  #[inline(always)]
+  pub fn put<F>(&mut self, setter: F) -> usize
+    where F : FnMut(&mut Option<T>)
+  {
+    // the buffer overwrites the oldest item, it never rejects one
+    self.writer.put(&self.ring, setter).expect("CircularBuffer rejected an item")
+  }
+
+  // the setter also receives the index within the batch, returns the seqno of the first
+  pub fn put_batch<F>(&mut self, n: usize, setter: F) -> usize
+    where F : FnMut(usize, &mut Option<T>)
+  {
+    self.writer.put_batch(&self.ring, n, setter).0
+  }
+
+  pub fn extend<I>(&mut self, items: I) -> usize
+    where I : IntoIterator<Item=T>
+  {
+    self.writer.extend(&self.ring, items)
+  }
+
+  // returns the items written since the last call, at most the last N
+  #[inline(always)]
+  pub fn iter(&mut self) -> CircularBufferIterator<'_, T> {
+    self.reader.iter(&self.ring)
+  }
+
+  // only the items returned by next() are consumed, see cb::CircularBuffer::iter_partial()
+  #[inline(always)]
+  pub fn iter_partial(&mut self) -> PartialIterator<'_, T> {
+    self.reader.iter_partial(&self.ring)
+  }
+
+  // the buffer stays borrowed while the two sides are in use
+  pub fn split(&mut self) -> (Writer<'_, T, N>, Reader<'_, T, N>) {
+    let writer = Writer { ring: &self.ring, writer: &mut self.writer };
+    let reader = Reader { ring: &self.ring, reader: &mut self.reader };
+    (writer, reader)
+  }
+}
+
+impl <'a, T: 'a, const N: usize> Writer<'a, T, N> {
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.ring.seqno()
+  }
+
+  // see CircularBuffer::put()
+  #[inline(always)]
+  pub fn put<F>(&mut self, setter: F) -> usize
+    where F : FnMut(&mut Option<T>)
+  {
+    self.writer.put(self.ring, setter).expect("CircularBuffer rejected an item")
+  }
+
+  // writes n items but publishes them at once, returns the seqno of the first
+  pub fn put_batch<F>(&mut self, n: usize, setter: F) -> usize
+    where F : FnMut(usize, &mut Option<T>)
+  {
+    self.writer.put_batch(self.ring, n, setter).0
+  }
+
+  pub fn extend<I>(&mut self, items: I) -> usize
+    where I : IntoIterator<Item=T>
+  {
+    self.writer.extend(self.ring, items)
+  }
+
+  // items that surely fit without overwriting an unread one
+  #[inline(always)]
+  pub fn room(&self) -> usize {
+    self.writer.room(self.ring)
+  }
+}
+
+impl <'a, T: 'a, const N: usize> Reader<'a, T, N> {
+  #[inline(always)]
+  pub fn unread(&self) -> usize {
+    self.reader.unread(self.ring)
+  }
+
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.reader.dropped()
+  }
+
+  #[inline(always)]
+  pub fn iter(&mut self) -> CircularBufferIterator<'_, T> {
+    self.reader.iter(self.ring)
+  }
+
+  #[inline(always)]
+  pub fn iter_partial(&mut self) -> PartialIterator<'_, T> {
+    self.reader.iter_partial(self.ring)
+  }
+}
+
+impl <T, const N: usize> Default for CircularBuffer<T, N> {
+  fn default() -> CircularBuffer<T, N> {
+    CircularBuffer::new()
+  }
+}
+
+impl <T, const N: usize> Storage<T> for Inline<T, N> {
+  #[inline(always)]
+  fn data(&self) -> &[UnsafeCell<Option<T>>] {
+    self.data.as_slice()
+  }
+
+  #[inline(always)]
+  fn stamps(&self) -> &[UnsafeCell<usize>] {
+    self.stamps.as_slice()
+  }
+
+  #[inline(always)]
+  fn buffer(&self) -> &[Flag] {
+    &self.buffer
+  }
+}
+
+impl <E, const N: usize> Elements<E, N> {
+  #[inline(always)]
+  fn as_slice(&self) -> &[E] {
+    // repr(C) puts last right behind the pairs, and arrays of a single
+    // type have no padding between their elements
+    unsafe { slice::from_raw_parts((self as *const Self).cast::<E>(), 2*N+1) }
+  }
+}
+
+#[cfg(test)]
+mod tests;
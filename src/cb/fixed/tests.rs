@@ -0,0 +1,96 @@
+use std::thread;
+use cb::IterRange;
+use cb::fixed::{CircularBuffer, Reader};
+
+#[test]
+fn put_iter() {
+  let mut x : CircularBuffer<i32, 4> = CircularBuffer::new();
+  assert_eq!(x.put(|v| *v = Some(1)), 0);
+  assert_eq!(x.put(|v| *v = Some(2)), 1);
+  {
+    let i = x.iter();
+    assert_eq!(i.get_range(), (0, 2));
+    assert_eq!(i.skipped(), 0);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![1, 2]);
+  }
+  assert_eq!(x.iter().next_id(), None);
+}
+
+#[test]
+fn overwrite_oldest() {
+  // 3 is not a power of two, 4 is
+  let mut x : CircularBuffer<usize, 3> = CircularBuffer::new();
+  let mut displaced = Vec::new();
+  x.put_batch(5, |i, v| {
+    if let Some(old) = v.take() { displaced.push(old); }
+    *v = Some(i);
+  });
+  // the item the last put pushed out is still in the writer's element
+  assert_eq!(displaced, vec![0]);
+  assert_eq!(x.unread(), 5);
+  {
+    let i = x.iter();
+    assert_eq!(i.skipped(), 2);
+    assert_eq!(i.get_range(), (2, 5));
+    assert_eq!(i.collect::<Vec<usize>>(), vec![2, 3, 4]);
+  }
+  let mut y : CircularBuffer<usize, 4> = CircularBuffer::default();
+  assert_eq!(y.extend(0..6), 0);
+  assert_eq!(y.iter().collect::<Vec<usize>>(), vec![2, 3, 4, 5]);
+  assert_eq!(y.dropped(), 2);
+  assert_eq!(x.dropped(), 2);
+}
+
+#[test]
+fn unconsumed_items_stay() {
+  let mut x : CircularBuffer<i32, 2> = CircularBuffer::new();
+  x.put(|v| *v = Some(1));
+  x.put(|v| *v = Some(2));
+  assert_eq!(x.iter().next(), Some(1));
+  // iter() consumed the range, the item left is not returned again
+  x.put(|v| *v = Some(3));
+  x.put(|v| *v = Some(4));
+  assert_eq!(x.iter().collect::<Vec<i32>>(), vec![3, 4]);
+  // its element goes back to the writer with it
+  let mut old = Vec::new();
+  for i in 5..8 {
+    x.put(|v| { old.push(v.take()); *v = Some(i); });
+  }
+  assert_eq!(old, vec![None, None, Some(2)]);
+}
+
+#[test]
+fn split_threads() {
+  let mut x : CircularBuffer<usize, 8> = CircularBuffer::new();
+  let (mut w, mut r) = x.split();
+  let n : usize = 100000;
+  let mut got : usize = 0;
+  let mut last : Option<usize> = None;
+  let mut read = |r: &mut Reader<'_, usize, 8>| {
+    let i = r.iter();
+    let (start, end) = i.get_range();
+    for (k, v) in i.enumerate() {
+      // every item carries its own seqno and they come in order
+      assert_eq!(v, start + k);
+      assert!(last.is_none_or(|l| l < v));
+      last = Some(v);
+      got += 1;
+    }
+    end
+  };
+  thread::scope(|s| {
+    let writer = s.spawn(move || {
+      for i in 0..n {
+        w.put(|v| *v = Some(i));
+      }
+    });
+    while !writer.is_finished() {
+      read(&mut r);
+    }
+    writer.join().unwrap();
+    assert_eq!(read(&mut r), n);
+  });
+  assert_eq!(last, Some(n-1));
+  assert_eq!(got + r.dropped(), n);
+  assert_eq!(r.unread(), 0);
+}
@@ -1,187 +1,1037 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+#[cfg(feature = "alloc")]
+use core::mem;
+use super::sync::{AtomicUsize, CachePadded, Ordering, UnsafeCell, pause};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
-pub struct CircularBuffer<T> {
-  seqno       : AtomicUsize,        // the ID of the last written item
-  seqno_priv  : usize,
-  data        : Vec<Option<T>>,     // (2*n)+1 preallocated elements
+#[cfg(feature = "alloc")]
+pub mod uninit;
+#[cfg(not(loom))]
+pub mod fixed;
+
+// Buffer flags and the head are tagged with the generation (ring lap) of
+// the item in their high bits. The low bits hold the position in data, or
+// the next ring position for the head. Each data element is also stamped
+// with the full seqno of its item, so the reader can verify what it got.
+//
+// The state is split by owner. The Ring is shared by both sides and only
+// accessed through &. A data element belongs to whoever holds its position:
+// the writer in write_tmp, the reader in read_priv, or the ring in buffer.
+// Positions only change hands through the atomic swaps on buffer.
+//
+// The writer stores seqno and head on every put while the reader only
+// loads them, so they sit on their own cache lines, away from the fields
+// both sides merely read. The flags can be padded too, at the cost of a
+// cache line per slot.
+//
+// The elements themselves are in the storage S: Vecs for the buffers
+// here, inline arrays for cb::fixed.
+//
+// A shared ring can also be watched by a third party that clones the items
+// in place. It pins an element while it reads, and whoever swaps a
+// position out of the ring waits for the pin to go before touching it.
+pub(crate) struct Ring<T, S> {
+  seqno       : CachePadded<AtomicUsize>,  // the ID of the next item to be written
+  head        : CachePadded<AtomicUsize>,  // generation and ring position of the next item
+  read_seqno  : CachePadded<AtomicUsize>,  // the oldest seqno the reader hasn't handed out, for the policy
+  slots       : S,                  // data, stamps and flags
+  #[cfg(feature = "std")]
+  timestamped : bool,
   size        : usize,              // n
+  pos_bits    : usize,              // low bits of the flags holding a position
+  _items      : PhantomData<T>,
+}
+
+// where a Ring keeps its elements, the protocol only sees the slices
+pub(crate) trait Storage<T> {
+  fn data(&self) -> &[UnsafeCell<Option<T>>];  // (2*n)+1 preallocated elements
+  fn stamps(&self) -> &[UnsafeCell<usize>];    // seqno of the item in each data element
+  fn buffer(&self) -> &[Flag];                 // all positions
+
+  // number of peekers reading each data element, empty unless peekable
+  fn pins(&self) -> &[AtomicUsize] { &[] }
+
+  // when each item was put, empty unless timestamped
+  #[cfg(feature = "std")]
+  fn times(&self) -> &[UnsafeCell<Instant>] { &[] }
+}
+
+#[cfg(feature = "alloc")]
+pub(crate) struct Heap<T> {
+  data        : Vec<UnsafeCell<Option<T>>>,
+  stamps      : Vec<UnsafeCell<usize>>,
+  #[cfg(feature = "std")]
+  times       : Vec<UnsafeCell<Instant>>,
+  buffer      : Vec<Flag>,
+  pins        : Vec<AtomicUsize>,
+}
+
+#[cfg(feature = "padded-slots")]
+type Flag = CachePadded<AtomicUsize>;
+#[cfg(not(feature = "padded-slots"))]
+type Flag = AtomicUsize;
+
+// the items are moved between the threads, only spsc::Peeker shares them
+// and it asks for T: Sync
+unsafe impl<T: Send, S: Storage<T>> Send for Ring<T, S> { }
+unsafe impl<T: Send, S: Storage<T>> Sync for Ring<T, S> { }
 
-  buffer      : Vec<AtomicUsize>,   // all positions
-  read_priv   : Vec<usize>,         // positions belong to the reader
+// state only the writer touches
+pub(crate) struct Writer {
+  seqno_priv  : usize,
   write_tmp   : usize,              // temporary position where the writer writes first
-  max_read    : usize,              // reader's last read seqno
+  write_pos   : usize,              // ring position of the next item
+  write_gen   : usize,              // generation of the next item
+  policy      : Policy,             // what to do when the reader is a full ring behind
+  rejected    : usize,              // items the policy did not let in
+}
+
+// What the writer does when the reader hasn't taken the last size items
+// yet. The writer never waits: it either overwrites or leaves the new item out.
+pub enum Policy {
+  // overwrite the oldest unread item, the default
+  OverwriteOldest,
+  // keep the unread items and reject the new one
+  RejectNewest,
+  // called with the number of unread items, true overwrites the oldest,
+  // false rejects the new one. it runs on the writer, so it must not block.
+  #[cfg(feature = "alloc")]
+  Hook(Box<dyn FnMut(usize) -> bool + Send>),
+}
+
+// state only the reader touches, P holds its n positions
+pub(crate) struct Reader<P> {
+  read_priv   : P,                  // positions belong to the reader
+  read_head   : usize,              // the head at the reader's last fetch
+  read_top    : usize,              // and the writer's seqno
+  max_read    : usize,              // the next seqno the reader expects
+  dropped     : usize,              // seqnos the reader never got to see
+
+  pending     : usize,              // items in read_priv not handed out by iter() yet
+  pend_start  : usize,              // seqno of the oldest pending item
+  pend_lost   : usize,              // seqnos lost since the last iter() call
+  #[cfg(feature = "std")]
+  expired     : usize,              // items iter_fresh() found too old
+}
+
+#[cfg(feature = "alloc")]
+pub struct CircularBuffer<T> {
+  ring        : Ring<T, Heap<T>>,
+  writer      : Writer,
+  reader      : Reader<Vec<usize>>,
 }
 
 pub struct CircularBufferIterator<'a, T: 'a> {
-  data   : &'a mut [Option<T>],
+  data   : &'a [UnsafeCell<Option<T>>],
   revpos : &'a [usize],
   start  : usize,
   count  : usize,
+  skipped: usize,
+}
+
+// leaves the items next() did not return to the reader
+pub struct PartialIterator<'a, T: 'a> {
+  inner      : CircularBufferIterator<'a, T>,
+  pending    : &'a mut usize,
+  pend_start : &'a mut usize,
+  read_seqno : &'a AtomicUsize,
+}
+
+// yields the items with their age, skipping the ones older than max_age
+#[cfg(feature = "std")]
+pub struct FreshIterator<'a, T: 'a> {
+  inner   : CircularBufferIterator<'a, T>,
+  times   : &'a [UnsafeCell<Instant>],
+  now     : Instant,
+  max_age : Duration,
+  expired : usize,
+  total   : &'a mut usize,              // the reader's expired count
+}
+
+pub struct PeekIterator<'a, T: 'a> {
+  data   : &'a [UnsafeCell<Option<T>>],
+  revpos : &'a [usize],
+  count  : usize,
 }
 
 pub trait IterRange {
   fn get_range(&self) -> (usize, usize);
   fn next_id(&self) -> Option<usize>;
+  // number of seqnos lost between the previous iter() call and this one
+  fn skipped(&self) -> usize;
 }
 
+// the generation has to outlive a reader stalled in the middle of a
+// scan, ask for at least this many laps before it repeats
+pub(crate) const MIN_GEN_BITS : usize = 12;
+
+// the initial flags belong to the generation before the first one
+const INITIAL_GEN : usize = usize::MAX;
+
+// the flags of a ring with n slots need this many bits for the position
+#[inline(always)]
+pub(crate) const fn pos_bits(size : usize) -> usize {
+  usize::BITS as usize - (2*size).leading_zeros() as usize
+}
+
+#[cfg(feature = "alloc")]
 impl <T> CircularBuffer<T> {
   pub fn new(size : usize) -> CircularBuffer<T> {
+    CircularBuffer::with_seqno(size, 0)
+  }
+
+  // every put records when it happened, so iter_fresh() can skip stale items
+  #[cfg(feature = "std")]
+  pub fn timestamped(size : usize) -> CircularBuffer<T> {
+    let mut ret = CircularBuffer::with_seqno(size, 0);
+    let now = Instant::now();
+    ret.ring.slots.times = (0..ret.ring.data().len()).map(|_i| UnsafeCell::new(now)).collect();
+    ret.ring.timestamped = true;
+    ret
+  }
+
+  pub fn with_policy(size : usize, policy : Policy) -> CircularBuffer<T> {
+    let mut ret = CircularBuffer::with_seqno(size, 0);
+    ret.writer.policy = policy;
+    ret
+  }
+
+  // starts the sequence numbers at seqno, tests use it to force a wraparound
+  pub(crate) fn with_seqno(size : usize, seqno : usize) -> CircularBuffer<T> {
 
     let mut size = size;
 
     // size cannot be zero, silently set to one
     if size == 0 { size = 1; }
 
-    let mut ret = CircularBuffer {
-      seqno       : AtomicUsize::new(0),
-      seqno_priv  : 0,
+    let bits = 8 * mem::size_of::<usize>();
+    let pos_bits = pos_bits(size);
+    if bits - pos_bits < MIN_GEN_BITS {
+      panic!("size: {} is too large, it leaves less than {} generation bits", size, MIN_GEN_BITS);
+    }
+    CircularBuffer::with_layout(size, seqno, pos_bits)
+  }
+
+  // keeps only gen_bits of the generation, so the model checks can turn
+  // it over within a few laps
+  #[cfg(any(loom, test))]
+  pub(crate) fn with_gen_bits(size : usize, seqno : usize, gen_bits : usize) -> CircularBuffer<T> {
+    let bits = 8 * mem::size_of::<usize>();
+    CircularBuffer::with_layout(size.max(1), seqno, bits - gen_bits)
+  }
+
+  fn with_layout(size : usize, seqno : usize, pos_bits : usize) -> CircularBuffer<T> {
+    let mut slots = Heap {
       data        : Vec::with_capacity(2*size+1),
-      size        : size,
+      stamps      : Vec::with_capacity(2*size+1),
+      #[cfg(feature = "std")]
+      times       : Vec::new(),
       buffer      : Vec::with_capacity(size),
-      read_priv   : Vec::with_capacity(size),
-      write_tmp   : 0,
-      max_read    : 0,
+      pins        : Vec::new(),
     };
 
+    let mut read_priv = Vec::with_capacity(size);
+
     // make sure there is enough place and fill it with the
     // default value (1+2*size)
-    ret.data.push(None);
+    slots.data.push(UnsafeCell::new(None));
 
     for i in 0..size {
-      ret.buffer.push(AtomicUsize::new(((1+i)<<4)+1));
-      ret.read_priv.push(1+size+i);
+      let flag = (INITIAL_GEN << pos_bits) | (1+i);
+      slots.buffer.push(Flag::from(AtomicUsize::new(flag)));
+      read_priv.push(1+size+i);
       // 2*size
-      ret.data.push(None);
-      ret.data.push(None);
+      slots.data.push(UnsafeCell::new(None));
+      slots.data.push(UnsafeCell::new(None));
     }
 
-    ret
+    // no item may look like it was already read
+    for _i in 0..(2*size+1) {
+      slots.stamps.push(UnsafeCell::new(seqno.wrapping_sub(1)));
+    }
+
+    let ring = Ring {
+      seqno       : CachePadded::new(AtomicUsize::new(seqno)),
+      head        : CachePadded::new(AtomicUsize::new(0)),
+      read_seqno  : CachePadded::new(AtomicUsize::new(seqno)),
+      slots,
+      #[cfg(feature = "std")]
+      timestamped : false,
+      size,
+      pos_bits,
+      _items      : PhantomData,
+    };
+
+    CircularBuffer {
+      ring,
+      writer      : Writer::new(seqno),
+      reader      : Reader::new(read_priv, seqno),
+    }
+  }
+
+  // hands the parts to separate owners, spsc keeps the ring in an Arc
+  // lets Ring::peek() clone the items in the ring while it is shared
+  pub(crate) fn peekable(mut self) -> CircularBuffer<T> {
+    self.ring.slots.pins = (0..self.ring.data().len()).map(|_i| AtomicUsize::new(0)).collect();
+    self
+  }
+
+  pub(crate) fn into_parts(self) -> (Ring<T, Heap<T>>, Writer, Reader<Vec<usize>>) {
+    (self.ring, self.writer, self.reader)
   }
 
   #[inline(always)]
   pub fn seqno(&self) -> usize {
-    self.seqno.load(Ordering::Acquire) >> 4
+    self.ring.seqno()
   }
 
+  // number of seqnos written since the last iter() call
+  #[inline(always)]
+  pub fn unread(&self) -> usize {
+    self.reader.unread(&self.ring)
+  }
+
+  // Returns the seqno of the item. A rejected item doesn't get one, put()
+  // returns the seqno it would have had then, see try_put().
   #[inline(always)]
   pub fn put<F>(&mut self, setter: F) -> usize
     where F : FnMut(&mut Option<T>)
+  {
+    self.try_put(setter).unwrap_or_else(|| self.seqno())
+  }
+
+  // returns the seqno of the item, None if the policy rejected it
+  #[inline(always)]
+  pub fn try_put<F>(&mut self, setter: F) -> Option<usize>
+    where F : FnMut(&mut Option<T>)
+  {
+    self.writer.put(&self.ring, setter)
+  }
+
+  // Writes n items but publishes the new seqno only once. The setter also
+  // receives the index within the batch. Returns the seqno of the first
+  // item and the number of items written, the policy may stop the batch
+  // early.
+  pub fn put_batch<F>(&mut self, n: usize, setter: F) -> (usize, usize)
+    where F : FnMut(usize, &mut Option<T>)
+  {
+    self.writer.put_batch(&self.ring, n, setter)
+  }
+
+  // puts all items with a single seqno update, returns the seqno of the first
+  pub fn extend<I>(&mut self, items: I) -> usize
+    where I : IntoIterator<Item=T>
+  {
+    self.writer.extend(&self.ring, items)
+  }
+
+  // puts value unless the policy rejects it, then it is returned
+  pub fn send(&mut self, value: T) -> Result<usize, T> {
+    self.writer.send(&self.ring, value)
+  }
+
+  // number of items the policy kept out so far
+  #[inline(always)]
+  pub fn rejected(&self) -> usize {
+    self.writer.rejected()
+  }
+
+  pub fn tmp<F>(&mut self, setter: F)
+    where F : FnMut(&mut Option<T>)
+  {
+    self.writer.tmp(&self.ring, setter)
+  }
+
+  #[inline(always)]
+  pub fn iter(&mut self) -> CircularBufferIterator<'_, T> {
+    self.reader.iter(&self.ring)
+  }
+
+  // Like iter(), but only the items returned by next() are consumed. The
+  // rest is returned again by the next iter() or iter_partial() call.
+  #[inline(always)]
+  pub fn iter_partial(&mut self) -> PartialIterator<'_, T> {
+    self.reader.iter_partial(&self.ring)
+  }
+
+  // Like iter(), but items put more than max_age ago are consumed without
+  // being returned. Without timestamps every item counts as fresh.
+  #[cfg(feature = "std")]
+  pub fn iter_fresh(&mut self, max_age: Duration) -> FreshIterator<'_, T> {
+    self.reader.iter_fresh(&self.ring, max_age)
+  }
+
+  // Moves the readable items out of the ring without consuming them. They
+  // stay with the reader and are returned by the next iter() call, unless
+  // the writer gets a full ring ahead of them first, then they are dropped.
+  pub fn peek_iter(&mut self) -> PeekIterator<'_, T> {
+    self.reader.peek_iter(&self.ring)
+  }
+
+  pub fn peek_latest(&mut self) -> Option<&T> {
+    self.reader.peek_latest(&self.ring)
+  }
+
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.reader.dropped()
+  }
+
+  // total number of items iter_fresh() skipped as too old
+  #[cfg(feature = "std")]
+  #[inline(always)]
+  pub fn expired(&self) -> usize {
+    self.reader.expired()
+  }
+}
+
+#[cfg(feature = "alloc")]
+impl <T> Storage<T> for Heap<T> {
+  #[inline(always)]
+  fn data(&self) -> &[UnsafeCell<Option<T>>] {
+    self.data.as_slice()
+  }
+
+  #[inline(always)]
+  fn stamps(&self) -> &[UnsafeCell<usize>] {
+    self.stamps.as_slice()
+  }
+
+  #[inline(always)]
+  fn buffer(&self) -> &[Flag] {
+    self.buffer.as_slice()
+  }
+
+  #[cfg(feature = "std")]
+  #[inline(always)]
+  fn times(&self) -> &[UnsafeCell<Instant>] {
+    self.times.as_slice()
+  }
+
+  #[inline(always)]
+  fn pins(&self) -> &[AtomicUsize] {
+    self.pins.as_slice()
+  }
+}
+
+impl <T, S: Storage<T>> Ring<T, S> {
+  #[inline(always)]
+  fn data(&self) -> &[UnsafeCell<Option<T>>] {
+    self.slots.data()
+  }
+
+  #[inline(always)]
+  fn stamps(&self) -> &[UnsafeCell<usize>] {
+    self.slots.stamps()
+  }
+
+  #[inline(always)]
+  fn buffer(&self) -> &[Flag] {
+    self.slots.buffer()
+  }
+
+  // the generation is truncated by the shift, comparisons are wraparound safe
+  #[inline(always)]
+  fn flag(&self, gen: usize, pos: usize) -> usize {
+    (gen << self.pos_bits) | pos
+  }
+
+  #[inline(always)]
+  fn pos_of(&self, flag: usize) -> usize {
+    flag & ((1 << self.pos_bits) - 1)
+  }
+
+  #[inline(always)]
+  fn gen_of(&self, flag: usize) -> usize {
+    flag >> self.pos_bits
+  }
+
+  #[inline(always)]
+  pub(crate) fn seqno(&self) -> usize {
+    self.seqno.load(Ordering::Acquire)
+  }
+
+  // Waits until no peeker reads the element at pos, the caller has just
+  // swapped it out. The pins are only changed by read-modify-writes, so
+  // either this one sees a peeker's pin or the peeker sees the new flag
+  // after pinning and leaves the element alone.
+  #[inline(always)]
+  fn unpinned(&self, pos: usize) {
+    if let Some(pin) = self.slots.pins().get(pos) {
+      while pin.compare_exchange(0, 0, Ordering::AcqRel, Ordering::Acquire).is_err() { pause(); }
+    }
+  }
+
+  // Clones the items in the ring the reader hasn't handed out, newest
+  // first. The ring must be peekable. It is a snapshot: the writer and the
+  // reader go on meanwhile, and the items the reader already swapped out
+  // are not in the ring anymore.
+  #[cfg(feature = "alloc")]
+  pub(crate) fn peek(&self, max: usize) -> Vec<T>
+    where T : Clone
+  {
+    // the reader reports a position it has seen the seqno pass, and the
+    // writer updates the seqno before the head. so every item the reader
+    // hasn't handed out is stamped in oldest..top.
+    let oldest : usize = self.read_seqno.load(Ordering::Acquire);
+    let head : usize = self.head.load(Ordering::Acquire);
+    let top : usize = self.seqno();
+    let unread : usize = top.wrapping_sub(oldest);
+
+    let mut ret = Vec::new();
+    let mut gen : usize = self.gen_of(head);
+    let mut pos : usize = self.pos_of(head);
+    for _i in 0..self.size {
+      if ret.len() == max { break; }
+      if pos == 0 {
+        pos = self.size - 1;
+        gen = gen.wrapping_sub(1);
+      } else {
+        pos -= 1;
+      }
+
+      let v = &self.buffer()[pos];
+      let old_flag : usize = v.load(Ordering::Acquire);
+      // the writer lapped us
+      if self.gen_of(old_flag) != self.gen_of(self.flag(gen, 0)) { break; }
+
+      // the elements the reader puts back may still hold an item it
+      // handed out, the stamp tells them apart
+      let elem : usize = self.pos_of(old_flag);
+      let pin = &self.slots.pins()[elem];
+      pin.fetch_add(1, Ordering::AcqRel);
+      let item = if v.load(Ordering::Acquire) == old_flag {
+        let stamp : usize = self.stamps()[elem].with(|s| unsafe { *s });
+        if stamp.wrapping_sub(oldest) < unread {
+          self.data()[elem].with(|d| unsafe { (*d).clone() })
+        } else {
+          None
+        }
+      } else {
+        None
+      };
+      pin.fetch_sub(1, Ordering::Release);
+      if let Some(item) = item { ret.push(item); }
+    }
+    ret
+  }
+}
+
+impl Writer {
+  // the first item gets seqno, it goes to write_tmp 0 and ring position 0
+  pub(crate) const fn new(seqno: usize) -> Writer {
+    Writer {
+      seqno_priv  : seqno,
+      write_tmp   : 0,
+      write_pos   : 0,
+      write_gen   : 0,
+      policy      : Policy::OverwriteOldest,
+      rejected    : 0,
+    }
+  }
+
+  // A rejected item doesn't get a seqno and its setter is not called.
+  // Nothing is published then and put() returns None.
+  #[inline(always)]
+  pub(crate) fn put<T, S: Storage<T>, F>(&mut self, ring: &Ring<T, S>, setter: F) -> Option<usize>
+    where F : FnMut(&mut Option<T>)
+  {
+    if !self.admit(ring) {
+      return None;
+    }
+    self.write(ring, setter);
+    Some(self.publish(ring))
+  }
+
+  // stops at the first rejected item. returns the seqno of the first item
+  // and the number of items written.
+  pub(crate) fn put_batch<T, S: Storage<T>, F>(&mut self, ring: &Ring<T, S>, n: usize, setter: F) -> (usize, usize)
+    where F : FnMut(usize, &mut Option<T>)
   {
     let mut setter = setter;
+    let mut count : usize = 0;
+    while count < n && self.admit(ring) {
+      self.write(ring, |v| setter(count, v));
+      count += 1;
+    }
+    if count == 0 {
+      return (self.seqno_priv, 0);
+    }
+    (self.publish(ring), count)
+  }
 
-    // get a reference to the data
-    let mut opt : Option<&mut Option<T>> = self.data.get_mut(self.write_tmp);
+  // the rejected items are dropped
+  pub(crate) fn extend<T, S: Storage<T>, I>(&mut self, ring: &Ring<T, S>, items: I) -> usize
+    where I : IntoIterator<Item=T>
+  {
+    for item in items {
+      if self.admit(ring) {
+        let mut item = Some(item);
+        self.write(ring, |v| *v = item.take());
+      }
+    }
+    self.publish(ring)
+  }
 
-    // calculate writer flag position
-    let mut serial  = self.seqno_priv;
-    let seqno       = serial >> 4;
-    let pos         = seqno % self.size;
+  // Like extend(), but the items found in the writer's element go to
+  // displaced with their seqno: these are the unread items the writes
+  // push out of the ring, each one right after the write that did it, and
+  // one an earlier put left there. The rejected items go there without a
+  // seqno. Returns the seqno of the first item and the number written.
+  #[cfg(feature = "alloc")]
+  pub(crate) fn extend_displacing<T, S: Storage<T>, I, D>(&mut self, ring: &Ring<T, S>, items: I, displaced: D) -> (usize, usize)
+    where I : IntoIterator<Item=T>, D : FnMut(Option<usize>, T)
+  {
+    let mut displaced = displaced;
+    let mut count : usize = 0;
+    self.take_tmp(ring, &mut displaced);
+    for item in items {
+      if !self.admit(ring) {
+        displaced(None, item);
+        continue;
+      }
+      let mut item = Some(item);
+      self.write(ring, |v| *v = item.take());
+      self.take_tmp(ring, &mut displaced);
+      count += 1;
+    }
+    (self.publish(ring), count)
+  }
 
-    if pos == 0 { serial += 1; }
+  // hands the item in the writer's element to displaced, if there is one
+  #[cfg(feature = "alloc")]
+  #[inline(always)]
+  fn take_tmp<T, S: Storage<T>, D>(&mut self, ring: &Ring<T, S>, displaced: &mut D)
+    where D : FnMut(Option<usize>, T)
+  {
+    if let Some(old) = ring.data()[self.write_tmp].with_mut(|v| unsafe { (*v).take() }) {
+      displaced(Some(self.tmp_seqno(ring)), old);
+    }
+  }
 
-    // write the data to the temporary writer buffer
-    match opt.as_mut() {
-      Some(v) => setter(v),
+  #[cfg(feature = "alloc")]
+  pub(crate) fn send<T, S: Storage<T>>(&mut self, ring: &Ring<T, S>, value: T) -> Result<usize, T> {
+    if !self.admit(ring) {
+      return Err(value);
+    }
+    let mut value = Some(value);
+    self.write(ring, |v| *v = value.take());
+    Ok(self.publish(ring))
+  }
+
+  // asks the policy if the next item may go in. the reader's seqno may
+  // be stale, that only makes the writer see more unread items.
+  #[inline(always)]
+  fn admit<T, S: Storage<T>>(&mut self, ring: &Ring<T, S>) -> bool {
+    if let Policy::OverwriteOldest = self.policy {
+      return true;
+    }
+    let unread : usize = self.unread(ring);
+    if unread < ring.size {
+      return true;
+    }
+    let admitted = match self.policy {
+      Policy::OverwriteOldest => true,
+      Policy::RejectNewest => false,
+      #[cfg(feature = "alloc")]
+      Policy::Hook(ref mut hook) => hook(unread),
+    };
+    if !admitted { self.rejected += 1; }
+    admitted
+  }
+
+  #[cfg(feature = "alloc")]
+  #[inline(always)]
+  pub(crate) fn rejected(&self) -> usize {
+    self.rejected
+  }
+
+  // seqnos written but not yet handed out by the reader, as far as it told
+  #[inline(always)]
+  fn unread<T, S: Storage<T>>(&self, ring: &Ring<T, S>) -> usize {
+    self.seqno_priv.wrapping_sub(ring.read_seqno.load(Ordering::Acquire))
+  }
+
+  // items that surely fit without overwriting an unread one
+  #[inline(always)]
+  pub(crate) fn room<T, S: Storage<T>>(&self, ring: &Ring<T, S>) -> usize {
+    ring.size.saturating_sub(self.unread(ring))
+  }
+
+  // writes a single item to the ring, readers won't look at it before publish()
+  #[inline(always)]
+  fn write<T, S: Storage<T>, F>(&mut self, ring: &Ring<T, S>, setter: F)
+    where F : FnMut(&mut Option<T>)
+  {
+    let mut setter = setter;
+
+    let seqno = self.seqno_priv;
+    let pos   = self.write_pos;
+
+    // write the data to the temporary writer buffer, write_tmp is ours
+    match ring.data().get(self.write_tmp) {
+      Some(v) => {
+        v.with_mut(|v| setter(unsafe { &mut *v }));
+        ring.stamps()[self.write_tmp].with_mut(|s| unsafe { *s = seqno });
+        #[cfg(feature = "std")]
+        if ring.timestamped {
+          ring.slots.times()[self.write_tmp].with_mut(|t| unsafe { *t = Instant::now() });
+        }
+      },
       None => {
         // this cannot happen under normal circumstances so the panic is only
         // left here to trigger crash during testing
-        panic!(format!("write_tmp: {} is invalid. size is {}, seqno is {}, serial is {}",
-          self.write_tmp, self.size, seqno, serial));
+        panic!("write_tmp: {} is invalid. size is {}, seqno is {}, pos is {}",
+          self.write_tmp, ring.size, seqno, pos);
       }
     }
 
     // get a reference to the writer flag
-    match self.buffer.get_mut(pos) {
+    let new_flag : usize = ring.flag(self.write_gen, self.write_tmp);
+    match ring.buffer().get(pos) {
       Some(v) => {
-        let new_flag : usize = (self.write_tmp << 4) | (serial & 0xf);
-        let result : usize = (*v).swap(new_flag, Ordering::AcqRel);
-        self.write_tmp = result >> 4;
+        let result : usize = v.swap(new_flag, Ordering::AcqRel);
+        self.write_tmp = ring.pos_of(result);
+        ring.unpinned(self.write_tmp);
       },
       None => {
         // this cannot happen under normal circumstances so the panic is only
         // left here to trigger crash during testing
-        panic!(format!("pos: {} is invalid. size is {}, seqno is {}, serial is {}",pos, self.size, seqno, serial));
+        panic!("pos: {} is invalid. size is {}, seqno is {}", pos, ring.size, seqno);
       }
     }
 
-    // increase sequence number and return the old one
-    self.seqno_priv = ((seqno+1) << 4) | (serial&0xf);
-    self.seqno.swap(self.seqno_priv, Ordering::AcqRel) >> 4
+    // step the private sequence number and ring position
+    self.seqno_priv = seqno.wrapping_add(1);
+    self.write_pos += 1;
+    if self.write_pos == ring.size {
+      self.write_pos = 0;
+      self.write_gen = self.write_gen.wrapping_add(1);
+    }
   }
 
-  pub fn tmp<F>(&mut self, setter: F)
+  // make the written items visible and return the old sequence number. the
+  // head goes last with SeqCst, so a parked reader either sees it or is
+  // seen as waiting.
+  #[inline(always)]
+  fn publish<T, S: Storage<T>>(&mut self, ring: &Ring<T, S>) -> usize {
+    let ret = ring.seqno.swap(self.seqno_priv, Ordering::AcqRel);
+    let head = ring.flag(self.write_gen, self.write_pos);
+    ring.head.store(head, Ordering::SeqCst);
+    ret
+  }
+
+  #[cfg(feature = "alloc")]
+  pub(crate) fn tmp<T, S: Storage<T>, F>(&mut self, ring: &Ring<T, S>, setter: F)
     where F : FnMut(&mut Option<T>)
   {
     let mut setter = setter;
 
-    // get a reference to the data
-    let mut opt : Option<&mut Option<T>> = self.data.get_mut(self.write_tmp);
-
     // write the data to the temporary writer buffer
-    match opt.as_mut() {
-      Some(v) => setter(v),
-      None => {}
+    if let Some(v) = ring.data().get(self.write_tmp) {
+      v.with_mut(|v| setter(unsafe { &mut *v }));
     }
   }
 
+  // the seqno of the item in the temporary writer buffer, if it holds one
+  #[cfg(feature = "alloc")]
   #[inline(always)]
-  pub fn iter(&mut self) -> CircularBufferIterator<T> {
+  pub(crate) fn tmp_seqno<T, S: Storage<T>>(&self, ring: &Ring<T, S>) -> usize {
+    ring.stamps()[self.write_tmp].with(|s| unsafe { *s })
+  }
+}
 
-    let mut serial : usize = self.seqno.load(Ordering::Acquire);
-    let mut seqno : usize  = serial >> 4;
-    let mut count : usize = 0;
+impl <P: AsRef<[usize]> + AsMut<[usize]>> Reader<P> {
+  // read_priv holds the spare positions n+1..2*n, the first item expected is seqno
+  pub(crate) const fn new(read_priv: P, seqno: usize) -> Reader<P> {
+    Reader {
+      read_priv,
+      read_head   : 0,
+      read_top    : seqno,
+      max_read    : seqno,
+      dropped     : 0,
+      pending     : 0,
+      pend_start  : 0,
+      pend_lost   : 0,
+      #[cfg(feature = "std")]
+      expired     : 0,
+    }
+  }
+
+  #[inline(always)]
+  pub(crate) fn unread<T, S: Storage<T>>(&self, ring: &Ring<T, S>) -> usize {
+    ring.seqno().wrapping_sub(self.max_read) + self.pending
+  }
+
+  // true if the next iter() has anything to look at. the head is the last
+  // thing the writer publishes, SeqCst pairs it with a parked reader.
+  #[cfg(feature = "alloc")]
+  #[inline(always)]
+  pub(crate) fn has_news<T, S: Storage<T>>(&self, ring: &Ring<T, S>) -> bool {
+    self.pending > 0 ||
+      ring.head.load(Ordering::SeqCst) != self.read_head ||
+      ring.seqno() != self.read_top
+  }
+
+  #[inline(always)]
+  pub(crate) fn iter<'a, T, S: Storage<T>>(&'a mut self, ring: &'a Ring<T, S>) -> CircularBufferIterator<'a, T> {
+    let (start, count, skipped) = self.take_pending(ring);
+    CircularBufferIterator {
+      data    : ring.data(),
+      revpos  : self.read_priv.as_ref(),
+      start,
+      count,
+      skipped,
+    }
+  }
+
+  // hands out everything pending, no matter how much of it gets consumed.
+  // returns the first seqno, the number of items and the lost seqnos.
+  #[inline(always)]
+  fn take_pending<T, S: Storage<T>>(&mut self, ring: &Ring<T, S>) -> (usize, usize, usize) {
+    self.fetch(ring);
+
+    let count : usize = self.pending;
+    let start : usize = if count > 0 { self.pend_start } else { self.max_read };
+    let skipped : usize = self.pend_lost;
+    self.pending = 0;
+    self.pend_lost = 0;
+    self.report(ring);
+    (start, count, skipped)
+  }
+
+  #[cfg(feature = "std")]
+  pub(crate) fn iter_fresh<'a, T, S: Storage<T>>(&'a mut self, ring: &'a Ring<T, S>, max_age: Duration) -> FreshIterator<'a, T> {
+    let (start, count, skipped) = self.take_pending(ring);
+    FreshIterator {
+      inner   : CircularBufferIterator {
+        data    : ring.data(),
+        revpos  : self.read_priv.as_ref(),
+        start,
+        count,
+        skipped,
+      },
+      times   : ring.slots.times(),
+      now     : Instant::now(),
+      max_age,
+      expired : 0,
+      total   : &mut self.expired,
+    }
+  }
+
+  #[cfg(feature = "std")]
+  #[inline(always)]
+  pub(crate) fn expired(&self) -> usize {
+    self.expired
+  }
+
+  #[inline(always)]
+  pub(crate) fn iter_partial<'a, T, S: Storage<T>>(&'a mut self, ring: &'a Ring<T, S>) -> PartialIterator<'a, T> {
+    self.fetch(ring);
+
+    let count : usize = self.pending;
+    let start : usize = if count > 0 { self.pend_start } else { self.max_read };
+    let skipped : usize = self.pend_lost;
+    self.pend_lost = 0;
+
+    PartialIterator {
+      inner       : CircularBufferIterator {
+        data    : ring.data(),
+        revpos  : self.read_priv.as_ref(),
+        start,
+        count,
+        skipped,
+      },
+      pending     : &mut self.pending,
+      pend_start  : &mut self.pend_start,
+      read_seqno  : &ring.read_seqno,
+    }
+  }
+
+  #[cfg(feature = "alloc")]
+  pub(crate) fn peek_iter<'a, T, S: Storage<T>>(&'a mut self, ring: &'a Ring<T, S>) -> PeekIterator<'a, T> {
+    self.fetch(ring);
+    PeekIterator {
+      data    : ring.data(),
+      revpos  : self.read_priv.as_ref(),
+      count   : self.pending,
+    }
+  }
+
+  #[cfg(feature = "alloc")]
+  pub(crate) fn peek_latest<'a, T, S: Storage<T>>(&'a mut self, ring: &'a Ring<T, S>) -> Option<&'a T> {
+    self.fetch(ring);
+    if self.pending > 0 {
+      ring.data()[self.read_priv.as_ref()[0]].with(|v| unsafe { (*v).as_ref() })
+    } else {
+      None
+    }
+  }
+
+  // Swaps the new items out of the ring into read_priv. The pending items
+  // occupy read_priv[0..pending] with the newest first, the rest is spare.
+  fn fetch<T, S: Storage<T>>(&mut self, ring: &Ring<T, S>) {
+
+    // the head repeats once its generation turns over, the seqno doesn't
+    let head : usize = ring.head.load(Ordering::Acquire);
+    let top : usize = ring.seqno.load(Ordering::Acquire);
+    if head == self.read_head && top == self.read_top { return; }
+    self.read_head = head;
+    self.read_top = top;
+
+    // the writer updates the seqno before the head, so this is an upper
+    // bound of the new items. give up the oldest pending ones if the new
+    // items may not fit.
     let max_read : usize = self.max_read;
-    self.max_read = seqno;
-
-    loop {
-      if count >= self.size || seqno <= max_read || seqno == 0 { break; }
-      let pos = (seqno-1) % self.size;
-
-      // this could be optimized to be iterator based
-      match self.read_priv.get_mut(count) {
-        Some(r) => {
-          match self.buffer.get_mut(pos) {
-            Some(v) => {
-              let old_flag : usize = (*v).load(Ordering::Acquire);
-
-              // turned over?
-              if old_flag&0xf != serial&0xf {
-                break;
-              }
-
-              // now try to swap out
-              let old_pos  : usize = old_flag >> 4;
-              let chk_flag : usize = (old_pos << 4) | (serial & 0xf);
-              let new_flag : usize = (*r << 4) | (serial & 0xf);
-
-              if chk_flag == (*v).compare_and_swap(chk_flag, new_flag, Ordering::AcqRel) {
-                *r = old_pos;
-                seqno -=1;
-                count += 1;
-              } else {
-                break;
-              }
-            },
-            None => {
-              // this cannot happen under any normal circumstances so just ignore it, but won't continue
-              panic!(format!("pos: {} is invalid. size is {}, count is {}, seqno is {}, serial is {}",pos, self.size, count, seqno, serial));
-            }
-          }
-        },
-        None => {
-          // this cannot happen under any normal circumstances so just ignore it, but won't continue
-          panic!(format!("count: {} is invalid. size is {}, pos is {}, seqno is {}, serial is {}",count, self.size, pos, seqno, serial));
+    let upper : usize = top.wrapping_sub(max_read);
+    let room : usize = ring.size - self.pending;
+    if upper > room && self.pending > 0 {
+      let lost = (upper - room).min(self.pending);
+      self.forget_pending(ring, self.pending - lost, self.pending);
+      self.pending -= lost;
+      self.pend_start = self.pend_start.wrapping_add(lost);
+    }
+
+    let pending : usize = self.pending;
+    let mut limit : usize = ring.size - pending;
+    let mut count : usize = 0;
+    let mut newest : usize = 0;
+    let mut gen : usize = ring.gen_of(head);
+    let mut pos : usize = ring.pos_of(head);
+
+    while count < limit {
+      // step back to the previous item
+      if pos == 0 {
+        pos = ring.size - 1;
+        gen = gen.wrapping_sub(1);
+      } else {
+        pos -= 1;
+      }
+
+      let r : usize = self.read_priv.as_ref()[pending+count];
+      let v = &ring.buffer()[pos];
+      let old_flag : usize = v.load(Ordering::Acquire);
+
+      // turned over?
+      if ring.gen_of(old_flag) != ring.gen_of(ring.flag(gen, 0)) {
+        break;
+      }
+
+      // now try to swap out
+      let new_flag : usize = ring.flag(gen, r);
+      if v.compare_exchange(old_flag, new_flag, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        break;
+      }
+      let old_pos : usize = ring.pos_of(old_flag);
+      ring.unpinned(old_pos);
+      self.read_priv.as_mut()[pending+count] = old_pos;
+
+      // the stamp tells what we really got. the first item decides how
+      // many are new, then they must follow each other without a gap.
+      let stamp : usize = ring.stamps()[old_pos].with(|s| unsafe { *s });
+      if count == 0 {
+        let new_items = stamp.wrapping_add(1).wrapping_sub(max_read);
+        if new_items == 0 || new_items > upper {
+          Self::discard(ring, old_pos, max_read);
+          break;
         }
+        newest = stamp;
+        limit = limit.min(new_items);
+      } else if stamp != newest.wrapping_sub(count) {
+        Self::discard(ring, old_pos, max_read);
+        break;
       }
+      count += 1;
+    }
 
-      if pos == 0 { serial -= 1; }
+    // the writer lapped us before we could take anything, the next fetch
+    // with a newer head will account for the loss
+    if count == 0 {
+      self.report(ring);
+      return;
     }
 
-    CircularBufferIterator {
-      data    : self.data.as_mut_slice(),
-      revpos  : self.read_priv.as_slice(),
-      start   : seqno,
-      count   : count,
+    // everything between the last read and the newest item that we could
+    // not swap out is gone: either overwritten or the writer turned over
+    self.max_read = newest.wrapping_add(1);
+    let skipped : usize = self.max_read.wrapping_sub(max_read) - count;
+    self.pend_lost += skipped;
+    self.dropped += skipped;
+
+    // the new items go in front of the older pending ones
+    self.read_priv.as_mut()[..pending+count].rotate_left(pending);
+
+    // pending items older than a gap would make the range ambiguous
+    if skipped > 0 && pending > 0 {
+      self.forget_pending(ring, count, count+pending);
+      self.pending = 0;
+    }
+
+    if self.pending == 0 { self.pend_start = self.max_read.wrapping_sub(count); }
+    self.pending += count;
+    self.report(ring);
+  }
+
+  // Tells the writer's policy where the reader is. The pending items are
+  // not handed out yet, so they still count as unread: peeking or a
+  // partial read must not make room for new items.
+  #[inline(always)]
+  fn report<T, S: Storage<T>>(&self, ring: &Ring<T, S>) {
+    ring.read_seqno.store(self.max_read.wrapping_sub(self.pending), Ordering::Release);
+  }
+
+  // Drops the item of an element swapped out but not taken. Its stamp goes
+  // back to a seqno already read, so when the element returns to the ring
+  // as a spare it can't pass for a new item, even if its flag aliases.
+  fn discard<T, S: Storage<T>>(ring: &Ring<T, S>, pos: usize, max_read: usize) {
+    ring.data()[pos].with_mut(|v| unsafe { *v = None });
+    ring.stamps()[pos].with_mut(|s| unsafe { *s = max_read.wrapping_sub(1) });
+  }
+
+  // drops the pending items at read_priv[from..to], they count as lost
+  fn forget_pending<T, S: Storage<T>>(&mut self, ring: &Ring<T, S>, from: usize, to: usize) {
+    for i in from..to {
+      ring.data()[self.read_priv.as_ref()[i]].with_mut(|v| unsafe { *v = None });
+    }
+    self.pend_lost += to - from;
+    self.dropped += to - from;
+  }
+
+  #[inline(always)]
+  pub(crate) fn dropped(&self) -> usize {
+    self.dropped
+  }
+}
+
+// mpsc hands out its items through this iterator too
+#[cfg(feature = "std")]
+impl <'a, T: 'a> CircularBufferIterator<'a, T> {
+  pub(crate) fn new(data: &'a [UnsafeCell<Option<T>>],
+                    revpos: &'a [usize],
+                    start: usize,
+                    count: usize,
+                    skipped: usize) -> CircularBufferIterator<'a, T> {
+    CircularBufferIterator { data, revpos, start, count, skipped, }
+  }
+}
+
+impl <'a, T: 'a> CircularBufferIterator<'a, T> {
+  // hands out the position of the next item instead of the item, for
+  // buffers keeping their values next to the ring
+  #[cfg(feature = "alloc")]
+  #[inline(always)]
+  pub(crate) fn next_pos(&mut self) -> Option<usize> {
+    if self.count > 0 {
+      self.count -= 1;
+      self.start = self.start.wrapping_add(1);
+      Some(self.revpos[self.count])
+    } else {
+      None
     }
   }
 }
@@ -191,25 +1041,57 @@ impl <'a, T: 'a> Iterator for CircularBufferIterator<'a, T> {
 
   #[inline(always)]
   fn next(&mut self) -> Option<T> {
-    use std::mem;
     if self.count > 0 {
       self.count -= 1;
-      self.start += 1;
+      self.start = self.start.wrapping_add(1);
       let pos : usize = self.revpos[self.count];
-      let mut ret : Option<T> = None;
-      mem::swap(&mut ret, &mut self.data[pos]);
-      ret
+      // the reader owns the positions in revpos
+      self.data[pos].with_mut(|v| unsafe { (*v).take() })
     } else {
       None
     }
   }
 }
 
+impl <'a, T: 'a> Iterator for PartialIterator<'a, T> {
+  type Item = T;
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<T> {
+    self.inner.next()
+  }
+}
+
+impl <'a, T: 'a> Drop for PartialIterator<'a, T> {
+  fn drop(&mut self) {
+    // the remaining items are still at revpos[0..count], newest first
+    *self.pending = self.inner.count;
+    *self.pend_start = self.inner.start;
+    self.read_seqno.store(self.inner.start, Ordering::Release);
+  }
+}
+
+impl <'a, T: 'a> Iterator for PeekIterator<'a, T> {
+  type Item = &'a T;
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<&'a T> {
+    while self.count > 0 {
+      self.count -= 1;
+      let pos : usize = self.revpos[self.count];
+      if let Some(v) = self.data[pos].with(|v| unsafe { (*v).as_ref() }) {
+        return Some(v);
+      }
+    }
+    None
+  }
+}
+
 impl <'a, T: 'a> IterRange for CircularBufferIterator<'a, T> {
 
   #[inline(always)]
   fn get_range(&self) -> (usize, usize) {
-    (self.start, self.start+self.count)
+    (self.start, self.start.wrapping_add(self.count))
   }
 
   #[inline(always)]
@@ -220,7 +1102,86 @@ impl <'a, T: 'a> IterRange for CircularBufferIterator<'a, T> {
       None
     }
   }
+
+  #[inline(always)]
+  fn skipped(&self) -> usize {
+    self.skipped
+  }
+}
+
+impl <'a, T: 'a> IterRange for PartialIterator<'a, T> {
+
+  #[inline(always)]
+  fn get_range(&self) -> (usize, usize) {
+    self.inner.get_range()
+  }
+
+  #[inline(always)]
+  fn next_id(&self) -> Option<usize> {
+    self.inner.next_id()
+  }
+
+  #[inline(always)]
+  fn skipped(&self) -> usize {
+    self.inner.skipped()
+  }
+}
+
+#[cfg(feature = "std")]
+impl <'a, T: 'a> FreshIterator<'a, T> {
+  // number of items this iterator skipped as too old so far
+  #[inline(always)]
+  pub fn expired(&self) -> usize {
+    self.expired
+  }
+}
+
+#[cfg(feature = "std")]
+impl <'a, T: 'a> Iterator for FreshIterator<'a, T> {
+  type Item = (T, Duration);
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<(T, Duration)> {
+    while self.inner.count > 0 {
+      let pos : usize = self.inner.revpos[self.inner.count-1];
+      let item : T = self.inner.next()?;
+      let age : Duration = match self.times.get(pos) {
+        Some(t) => self.now.saturating_duration_since(t.with(|t| unsafe { *t })),
+        None => Duration::ZERO,
+      };
+      if age <= self.max_age {
+        return Some((item, age));
+      }
+      self.expired += 1;
+      *self.total += 1;
+    }
+    None
+  }
+}
+
+#[cfg(feature = "std")]
+impl <'a, T: 'a> IterRange for FreshIterator<'a, T> {
+
+  #[inline(always)]
+  fn get_range(&self) -> (usize, usize) {
+    self.inner.get_range()
+  }
+
+  #[inline(always)]
+  fn next_id(&self) -> Option<usize> {
+    self.inner.next_id()
+  }
+
+  #[inline(always)]
+  fn skipped(&self) -> usize {
+    self.inner.skipped()
+  }
 }
 
-#[cfg(test)]
+// the tests are older than these lints
+#[cfg(all(test, feature = "alloc", not(loom)))]
+#[allow(unused_parens, clippy::unnecessary_cast, clippy::bool_assert_comparison,
+        clippy::unnecessary_fold)]
 pub mod tests;
+#[cfg(all(test, loom))]
+mod model;
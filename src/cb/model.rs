@@ -0,0 +1,154 @@
+// Exhaustive model checks with loom, run them with:
+//   RUSTFLAGS="--cfg loom" cargo test --release --lib cb::model
+use cb::*;
+use loom;
+use loom::sync::Arc;
+use loom::thread;
+
+// reads once, every item must carry its own seqno and come after the last one
+fn read(x: &mut Reader<Vec<usize>>, ring: &Ring<usize, Heap<usize>>, last: &mut Option<usize>) -> usize {
+  let i = x.iter(ring);
+  let (start, end) = i.get_range();
+  let got : Vec<usize> = i.collect();
+  assert_eq!(end.wrapping_sub(start), got.len());
+  for (k, v) in got.iter().enumerate() {
+    assert_eq!(*v, start.wrapping_add(k));
+  }
+  if let (Some(l), Some(f)) = (*last, got.first()) {
+    assert!(f.wrapping_sub(l).wrapping_sub(1) < usize::MAX / 2);
+  }
+  if let Some(l) = got.last() {
+    *last = Some(*l);
+  }
+  got.len()
+}
+
+// n puts race with the given number of reads, then a final read
+// after the writer is gone has to see the last item
+fn run(size: usize, first: usize, n: usize, reads: usize) {
+  loom::model(move || {
+    // the writer and the reader share the ring the same way spsc does
+    let (ring, mut w, mut b) = CircularBuffer::with_seqno(size, first).into_parts();
+    let ring = Arc::new(ring);
+    let r = ring.clone();
+    let writer = thread::spawn(move || {
+      for i in 0..n {
+        w.put(&*r, |v| *v = Some(first.wrapping_add(i)));
+      }
+    });
+
+    let mut last = None;
+    let mut got = 0;
+    for _i in 0..reads {
+      got += read(&mut b, &ring, &mut last);
+    }
+    writer.join().unwrap();
+    got += read(&mut b, &ring, &mut last);
+
+    assert_eq!(last, Some(first.wrapping_add(n-1)));
+    assert_eq!(got + b.dropped(), n);
+    assert_eq!(b.unread(&ring), 0);
+  });
+}
+
+#[test]
+fn reader_mid_scan() {
+  // the writer keeps putting while the reader swaps the ring out
+  run(2, 0, 2, 1);
+}
+
+#[test]
+fn writer_overtakes_reader() {
+  // a single slot is overwritten under the reader's feet
+  run(1, 0, 3, 1);
+}
+
+#[test]
+fn writer_laps_during_scan() {
+  // the generation turns over between the head load and the swaps
+  run(2, 0, 5, 1);
+}
+
+#[test]
+fn repeated_reads() {
+  run(2, 0, 3, 2);
+  run(3, 0, 6, 2);
+}
+
+#[test]
+fn seqno_turnover() {
+  run(2, usize::MAX - 1, 4, 1);
+}
+
+// With gen_bits the flags repeat after a few laps. A writer lapping the
+// reader's scan that often may cost the newest items, but the stamps
+// have to keep out the stale and repeated ones, and the next put after
+// the race has to get through again.
+fn turnover(size: usize, gen_bits: usize, n: usize) {
+  loom::model(move || {
+    let (ring, mut w, mut b) = CircularBuffer::with_gen_bits(size, 0, gen_bits).into_parts();
+    let ring = Arc::new(ring);
+    let r = ring.clone();
+    let writer = thread::spawn(move || {
+      for i in 0..n {
+        w.put(&*r, |v| *v = Some(i));
+      }
+      w
+    });
+
+    let mut last = None;
+    let mut got = read(&mut b, &ring, &mut last);
+    let mut w = writer.join().unwrap();
+    got += read(&mut b, &ring, &mut last);
+    assert!(got + b.dropped() <= n);
+
+    w.put(&*ring, |v| *v = Some(n));
+    read(&mut b, &ring, &mut last);
+    assert_eq!(last, Some(n));
+  });
+}
+
+#[test]
+fn generation_turnover() {
+  // the flags repeat every second lap
+  turnover(1, 1, 4);
+  turnover(2, 1, 5);
+}
+
+// a peeker clones the items in place while the writer and the reader swap
+// the elements out, loom reports it if any of them touches a pinned one
+fn peeked(size: usize, n: usize) {
+  // three threads, bound the preemptions to keep it quick
+  let mut model = loom::model::Builder::new();
+  model.preemption_bound = Some(3);
+  model.check(move || {
+    let (ring, mut w, mut b) = CircularBuffer::with_seqno(size, 0).peekable().into_parts();
+    let ring = Arc::new(ring);
+    let r = ring.clone();
+    let writer = thread::spawn(move || {
+      for i in 0..n {
+        w.put(&*r, |v| *v = Some(i));
+      }
+    });
+    let p = ring.clone();
+    let peeker = thread::spawn(move || {
+      let got = p.peek(usize::MAX);
+      assert!(got.len() <= size);
+      assert!(got.iter().all(|v| *v < n));
+      assert!(got.windows(2).all(|v| v[0] > v[1]));
+    });
+
+    let mut last = None;
+    let mut got = read(&mut b, &ring, &mut last);
+    writer.join().unwrap();
+    peeker.join().unwrap();
+    got += read(&mut b, &ring, &mut last);
+    assert_eq!(got + b.dropped(), n);
+  });
+}
+
+#[test]
+fn peek_while_swapping() {
+  peeked(1, 2);
+  peeked(2, 3);
+}
@@ -282,3 +282,342 @@ fn read_twice() {
   assert_eq!(x.iter().count(), 2);
   assert_eq!(x.iter().count(), 0);
 }
+
+#[test]
+fn skipped_none() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(5);
+  x.put(|v| *v = Some(1));
+  x.put(|v| *v = Some(2));
+  x.put(|v| *v = Some(3));
+  let i = x.iter();
+  assert_eq!(i.skipped(), 0);
+  assert_eq!(i.count(), 3);
+  assert_eq!(x.dropped(), 0);
+}
+
+#[test]
+fn skipped_overwritten() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(3);
+  for i in 0..5 {
+    x.put(|v| *v = Some(i));
+  }
+  let i = x.iter();
+  let (from, to) = i.get_range();
+  assert_eq!(from, 2);
+  assert_eq!(to, 5);
+  assert_eq!(i.skipped(), 2);
+  assert_eq!(x.dropped(), 2);
+  x.put(|v| *v = Some(5));
+  let i = x.iter();
+  assert_eq!(i.skipped(), 0);
+  for i in 0..10 {
+    x.put(|v| *v = Some(i));
+  }
+  let i = x.iter();
+  assert_eq!(i.skipped(), 7);
+  assert_eq!(i.count(), 3);
+  assert_eq!(x.dropped(), 9);
+}
+
+#[test]
+fn skipped_empty() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::<i32>::new(2);
+  assert_eq!(x.iter().skipped(), 0);
+  assert_eq!(x.dropped(), 0);
+}
+
+#[test]
+fn peek_keeps_items() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(4);
+  assert_eq!(x.peek_latest(), None);
+  x.put(|v| *v = Some(1));
+  x.put(|v| *v = Some(2));
+  assert_eq!(x.peek_latest(), Some(&2));
+  x.put(|v| *v = Some(3));
+  assert_eq!(x.peek_iter().cloned().collect::<Vec<i32>>(), vec![1, 2, 3]);
+  assert_eq!(x.unread(), 3);
+  let i = x.iter();
+  assert_eq!(i.get_range(), (0, 3));
+  assert_eq!(i.skipped(), 0);
+  assert_eq!(i.collect::<Vec<i32>>(), vec![1, 2, 3]);
+  assert_eq!(x.peek_iter().count(), 0);
+  assert_eq!(x.iter().count(), 0);
+}
+
+#[test]
+fn peek_then_overflow() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(3);
+  x.put(|v| *v = Some(0));
+  x.put(|v| *v = Some(1));
+  assert_eq!(x.peek_iter().count(), 2);
+  // the reader holds at most 3 items, the oldest peeked one goes
+  x.put(|v| *v = Some(2));
+  x.put(|v| *v = Some(3));
+  assert_eq!(x.peek_latest(), Some(&3));
+  let i = x.iter();
+  assert_eq!(i.get_range(), (1, 4));
+  assert_eq!(i.skipped(), 1);
+  assert_eq!(i.collect::<Vec<i32>>(), vec![1, 2, 3]);
+  assert_eq!(x.dropped(), 1);
+  // a gap after the peeked items drops them too
+  x.put(|v| *v = Some(4));
+  assert_eq!(x.peek_latest(), Some(&4));
+  for i in 5..10 {
+    x.put(|v| *v = Some(i));
+  }
+  let i = x.iter();
+  assert_eq!(i.get_range(), (7, 10));
+  assert_eq!(i.skipped(), 3);
+  assert_eq!(i.collect::<Vec<i32>>(), vec![7, 8, 9]);
+  assert_eq!(x.dropped(), 4);
+}
+
+#[test]
+fn partial_keeps_rest() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(4);
+  x.put(|v| *v = Some(1));
+  x.put(|v| *v = Some(2));
+  x.put(|v| *v = Some(3));
+  {
+    let mut i = x.iter_partial();
+    assert_eq!(i.next(), Some(1));
+  }
+  assert_eq!(x.unread(), 2);
+  x.put(|v| *v = Some(4));
+  {
+    let mut i = x.iter_partial();
+    assert_eq!(i.get_range(), (1, 4));
+    assert_eq!(i.next(), Some(2));
+    assert_eq!(i.next(), Some(3));
+  }
+  // a plain iter() consumes the rest as before
+  let i = x.iter();
+  assert_eq!(i.get_range(), (3, 4));
+  assert_eq!(x.iter_partial().count(), 0);
+}
+
+#[test]
+fn partial_overflow() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(2);
+  x.put(|v| *v = Some(1));
+  x.put(|v| *v = Some(2));
+  assert_eq!(x.iter_partial().get_range(), (0, 2));
+  // the unconsumed items are the oldest ones to go
+  x.put(|v| *v = Some(3));
+  {
+    let i = x.iter_partial();
+    assert_eq!(i.skipped(), 1);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![2, 3]);
+  }
+  assert_eq!(x.unread(), 0);
+  assert_eq!(x.dropped(), 1);
+}
+
+#[test]
+fn put_batch_once() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(5);
+  x.put(|v| *v = Some(0));
+  assert_eq!(x.put_batch(3, |i, v| *v = Some(10+i)), (1, 3));
+  assert_eq!(x.seqno(), 4);
+  let i = x.iter();
+  assert_eq!(i.get_range(), (0, 4));
+  assert_eq!(i.collect::<Vec<usize>>(), vec![0, 10, 11, 12]);
+  assert_eq!(x.put_batch(0, |_i, v| *v = Some(0)), (4, 0));
+  assert_eq!(x.iter().count(), 0);
+}
+
+#[test]
+fn put_batch_overflow() {
+  use cb::IterRange;
+  let mut x = CircularBuffer::new(3);
+  // the setter sees the items displaced within the batch
+  let mut displaced = Vec::new();
+  x.put_batch(7, |i, v| {
+    if let Some(old) = v.take() { displaced.push(old); }
+    *v = Some(i);
+  });
+  assert_eq!(displaced, vec![0, 1, 2]);
+  let i = x.iter();
+  assert_eq!(i.skipped(), 4);
+  assert_eq!(i.collect::<Vec<usize>>(), vec![4, 5, 6]);
+}
+
+#[test]
+fn extend_items() {
+  let mut x = CircularBuffer::new(4);
+  assert_eq!(x.extend(vec!["a", "b"]), 0);
+  assert_eq!(x.extend(vec!["c"]), 2);
+  assert_eq!(x.iter().collect::<Vec<&str>>(), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn seqno_wraparound() {
+  use cb::IterRange;
+  let start = usize::MAX - 3;
+  let mut x = CircularBuffer::with_seqno(3, start);
+  for i in 0..3 { x.put(|v| *v = Some(i)); }
+  let i = x.iter();
+  assert_eq!(i.get_range(), (start, start.wrapping_add(3)));
+  assert_eq!(i.collect::<Vec<i32>>(), vec![0, 1, 2]);
+  // the seqno passes zero in the middle of these
+  for i in 3..8 { x.put(|v| *v = Some(i)); }
+  assert_eq!(x.seqno(), 4);
+  assert_eq!(x.unread(), 5);
+  let i = x.iter();
+  assert_eq!(i.skipped(), 2);
+  assert_eq!(i.get_range(), (1, 4));
+  assert_eq!(i.collect::<Vec<i32>>(), vec![5, 6, 7]);
+  assert_eq!(x.dropped(), 2);
+}
+
+#[test]
+fn many_laps() {
+  // far more laps than a 4 bit serial could tell apart
+  for size in 1..5 {
+    let mut x = CircularBuffer::with_seqno(size, usize::MAX - 100);
+    let mut next = 0usize;
+    let mut lost = 0usize;
+    for round in 0..300usize {
+      let n = round % (3*size);
+      for _i in 0..n { x.put(|v| *v = Some(next)); next += 1; }
+      let i = x.iter();
+      let skipped = i.skipped();
+      let got : Vec<usize> = i.collect();
+      assert_eq!(got.len(), n.min(size));
+      for (k, v) in got.iter().enumerate() {
+        assert_eq!(*v, next - got.len() + k);
+      }
+      lost += skipped;
+    }
+    assert_eq!(x.dropped(), lost);
+    assert_eq!(x.unread(), 0);
+  }
+}
+
+mod uninit {
+  use cb::IterRange;
+  use cb::uninit::*;
+  use std::cell::Cell;
+  use std::rc::Rc;
+
+  struct Counted(Rc<Cell<usize>>);
+
+  impl Drop for Counted {
+    fn drop(&mut self) {
+      self.0.set(self.0.get() + 1);
+    }
+  }
+
+  #[test]
+  fn push_iter() {
+    let mut x = UninitBuffer::new(3);
+    for i in 0..5 { x.push(i); }
+    let i = x.iter();
+    assert_eq!(i.skipped(), 2);
+    assert_eq!(i.get_range(), (2, 5));
+    assert_eq!(i.collect::<Vec<i32>>(), vec![2, 3, 4]);
+    assert_eq!(x.dropped(), 2);
+    assert_eq!(x.unread(), 0);
+  }
+
+  #[test]
+  fn put_in_place() {
+    let mut x : UninitBuffer<[u32; 4]> = UninitBuffer::new(1);
+    // the first element is fresh, the next ones still hold overwritten items
+    let mut seen = Vec::new();
+    for i in 0..4 {
+      x.put(|v| { seen.push(v[0]); v[0] = i; });
+    }
+    assert_eq!(seen, vec![0, 0, 0, 1]);
+    assert_eq!(x.iter().map(|v| v[0]).collect::<Vec<u32>>(), vec![3]);
+  }
+
+  #[test]
+  fn drops_live_items() {
+    let drops = Rc::new(Cell::new(0));
+    {
+      let mut x = UninitBuffer::new(2);
+      for _i in 0..5 { x.push(Counted(drops.clone())); }
+      // the overwritten items are dropped when their element is reused
+      assert_eq!(drops.get(), 2);
+      {
+        let mut i = x.iter_partial();
+        drop(i.next());
+      }
+      assert_eq!(drops.get(), 3);
+    }
+    assert_eq!(drops.get(), 5);
+  }
+
+  #[test]
+  fn drops_unconsumed_items() {
+    let drops = Rc::new(Cell::new(0));
+    {
+      let mut x = UninitBuffer::new(2);
+      x.push(Counted(drops.clone()));
+      x.push(Counted(drops.clone()));
+      // iter() consumes both, the one next() did not return stays until
+      // its element is reused or the buffer goes away
+      drop(x.iter().next());
+      assert_eq!(drops.get(), 1);
+      for _i in 0..4 { x.push(Counted(drops.clone())); }
+      assert_eq!(drops.get(), 2);
+      assert_eq!(x.iter().count(), 2);
+      assert_eq!(drops.get(), 4);
+    }
+    assert_eq!(drops.get(), 6);
+  }
+
+  // drops once, then panics the first time
+  struct Bomb(Rc<Cell<usize>>, bool);
+
+  impl Drop for Bomb {
+    fn drop(&mut self) {
+      self.0.set(self.0.get() + 1);
+      if self.1 { panic!("boom"); }
+    }
+  }
+
+  #[test]
+  fn push_panicking_drop() {
+    use std::panic::{self, AssertUnwindSafe};
+    let drops = Rc::new(Cell::new(0));
+    {
+      let mut x = UninitBuffer::new(1);
+      x.push(Bomb(drops.clone(), true));
+      x.push(Bomb(drops.clone(), false));
+      // the next element still holds the first item
+      let r = panic::catch_unwind(AssertUnwindSafe(|| { x.push(Bomb(drops.clone(), false)); }));
+      assert!(r.is_err());
+      // the first item and the one being pushed, each once
+      assert_eq!(drops.get(), 2);
+      x.push(Bomb(drops.clone(), false));
+    }
+    assert_eq!(drops.get(), 4);
+  }
+}
+
+#[test]
+fn head_turnover() {
+  use cb::IterRange;
+  // with a single generation bit the head repeats every second lap, the
+  // reader must not take that for an unchanged ring
+  let mut x = CircularBuffer::with_gen_bits(1, 0, 1);
+  x.put(|v| *v = Some(0));
+  x.put(|v| *v = Some(1));
+  assert_eq!(x.iter().collect::<Vec<i32>>(), vec![1]);
+  x.put(|v| *v = Some(2));
+  x.put(|v| *v = Some(3));
+  let i = x.iter();
+  assert_eq!(i.skipped(), 1);
+  assert_eq!(i.collect::<Vec<i32>>(), vec![3]);
+  assert_eq!(x.dropped(), 2);
+}
@@ -0,0 +1,181 @@
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::mem::MaybeUninit;
+use super::{CircularBuffer, CircularBufferIterator, PartialIterator, IterRange};
+
+// A CircularBuffer without an Option around every item. The values sit in
+// MaybeUninit elements next to the ring, and the ring only moves their
+// positions around: its own elements hold nothing and take no space. A
+// bit per element tells which values are initialized.
+pub struct UninitBuffer<T> {
+  inner   : CircularBuffer<Infallible>,
+  values  : Vec<MaybeUninit<T>>,       // (2*n)+1 elements, indexed like the ring's data
+  live    : Vec<usize>,                // a bit per value, set while it is initialized
+}
+
+pub struct UninitIterator<'a, T: 'a, I = CircularBufferIterator<'a, Infallible>> {
+  inner   : I,
+  values  : &'a mut [MaybeUninit<T>],
+  live    : &'a mut [usize],
+}
+
+const BITS : usize = usize::BITS as usize;
+
+#[inline(always)]
+fn set_live(live: &mut [usize], pos: usize) {
+  live[pos / BITS] |= 1 << (pos % BITS);
+}
+
+// clears the bit of pos, true if the value was initialized
+#[inline(always)]
+fn clear_live(live: &mut [usize], pos: usize) -> bool {
+  let bit = 1 << (pos % BITS);
+  let was = live[pos / BITS] & bit != 0;
+  live[pos / BITS] &= !bit;
+  was
+}
+
+impl <T> UninitBuffer<T> {
+  pub fn new(size : usize) -> UninitBuffer<T> {
+    let inner : CircularBuffer<Infallible> = CircularBuffer::new(size);
+    let len = inner.ring.data().len();
+    let values = (0..len).map(|_i| MaybeUninit::uninit()).collect();
+    let live = alloc::vec![0; len.div_ceil(BITS)];
+    UninitBuffer { inner, values, live, }
+  }
+
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.inner.seqno()
+  }
+
+  #[inline(always)]
+  pub fn unread(&self) -> usize {
+    self.inner.unread()
+  }
+
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.inner.dropped()
+  }
+
+  // moves value into the writer's element, an item overwritten earlier
+  // is dropped there. returns the seqno of the item.
+  #[inline(always)]
+  pub fn push(&mut self, value: T) -> usize {
+    let pos = self.inner.writer.write_tmp;
+    // the bit is gone before the old value drops, so a panicking drop
+    // cannot leave it behind for a second one
+    if clear_live(&mut self.live, pos) {
+      unsafe { self.values[pos].assume_init_drop(); }
+    }
+    self.values[pos].write(value);
+    set_live(&mut self.live, pos);
+    self.publish()
+  }
+
+  // Updates the writer's element in place. It still holds the item
+  // overwritten there if there was one, otherwise a default value.
+  #[inline(always)]
+  pub fn put<F>(&mut self, setter: F) -> usize
+    where T : Default, F : FnOnce(&mut T)
+  {
+    let pos = self.inner.writer.write_tmp;
+    if !clear_live(&mut self.live, pos) {
+      self.values[pos].write(T::default());
+    }
+    set_live(&mut self.live, pos);
+    setter(unsafe { self.values[pos].assume_init_mut() });
+    self.publish()
+  }
+
+  // hands the writer's element to the ring
+  #[inline(always)]
+  fn publish(&mut self) -> usize {
+    self.inner.put(|_v| {})
+  }
+
+  #[inline(always)]
+  pub fn iter(&mut self) -> UninitIterator<'_, T> {
+    UninitIterator {
+      inner   : self.inner.iter(),
+      values  : self.values.as_mut_slice(),
+      live    : self.live.as_mut_slice(),
+    }
+  }
+
+  // only the items returned by next() are consumed, see CircularBuffer::iter_partial()
+  #[inline(always)]
+  pub fn iter_partial(&mut self) -> UninitIterator<'_, T, PartialIterator<'_, Infallible>> {
+    UninitIterator {
+      inner   : self.inner.iter_partial(),
+      values  : self.values.as_mut_slice(),
+      live    : self.live.as_mut_slice(),
+    }
+  }
+}
+
+// the unread and the overwritten items are still initialized
+impl <T> Drop for UninitBuffer<T> {
+  fn drop(&mut self) {
+    for pos in 0..self.values.len() {
+      if clear_live(&mut self.live, pos) {
+        unsafe { self.values[pos].assume_init_drop(); }
+      }
+    }
+  }
+}
+
+impl <'a, T: 'a, I> UninitIterator<'a, T, I> {
+  // moves the value out of a position the ring handed out
+  #[inline(always)]
+  fn take(&mut self, pos: usize) -> Option<T> {
+    if clear_live(self.live, pos) {
+      Some(unsafe { self.values[pos].assume_init_read() })
+    } else {
+      None
+    }
+  }
+}
+
+impl <'a, T: 'a> Iterator for UninitIterator<'a, T> {
+  type Item = T;
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<T> {
+    loop {
+      let pos = self.inner.next_pos()?;
+      if let Some(v) = self.take(pos) { return Some(v); }
+    }
+  }
+}
+
+impl <'a, T: 'a> Iterator for UninitIterator<'a, T, PartialIterator<'a, Infallible>> {
+  type Item = T;
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<T> {
+    loop {
+      let pos = self.inner.inner.next_pos()?;
+      if let Some(v) = self.take(pos) { return Some(v); }
+    }
+  }
+}
+
+impl <'a, T: 'a, I: IterRange> IterRange for UninitIterator<'a, T, I> {
+
+  #[inline(always)]
+  fn get_range(&self) -> (usize, usize) {
+    self.inner.get_range()
+  }
+
+  #[inline(always)]
+  fn next_id(&self) -> Option<usize> {
+    self.inner.next_id()
+  }
+
+  #[inline(always)]
+  fn skipped(&self) -> usize {
+    self.inner.skipped()
+  }
+}
@@ -0,0 +1,190 @@
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::cb::{CircularBuffer, Heap, Ring, Writer, Reader};
+
+// latest flag bit of an item the reader has not taken yet
+const FRESH : usize = 1 << (usize::BITS - 1);
+
+// Every key gets its own slot with three elements, like a triple buffer:
+// one in latest, one spare for the writer and one spare for the reader.
+// Putting swaps the writer's spare into latest, so a fresh item that was
+// not read yet is simply replaced. Reading swaps the reader's spare in.
+struct Slot<K, V> {
+  latest      : AtomicUsize,                  // FRESH | position in data
+  data        : [UnsafeCell<Option<(K, V)>>; 3],
+}
+
+struct SharedBuffer<K, V> {
+  slots       : Vec<Slot<K, V>>,              // one per key, up to the capacity
+  ring        : Ring<usize, Heap<usize>>,     // slots that turned fresh, in order
+}
+
+// the items are moved between the threads, but never shared
+unsafe impl<K: Send, V: Send> Sync for SharedBuffer<K, V> { }
+unsafe impl<K: Send, V: Send> Send for SharedBuffer<K, V> { }
+
+pub struct Sender<K, V> {
+  inner       : Arc<SharedBuffer<K, V>>,
+  writer      : Writer,
+  keys        : HashMap<K, usize>,            // the slot of each key
+  spare       : Vec<usize>,                   // the writer's position in each slot
+  conflated   : usize,                        // unread items replaced by a newer one
+}
+
+pub struct Receiver<K, V> {
+  inner       : Arc<SharedBuffer<K, V>>,
+  reader      : Reader<Vec<usize>>,
+  spare       : Vec<usize>,                   // the reader's position in each slot
+  ready       : Vec<usize>,                   // slots taken by the last iter()
+}
+
+pub struct ConflateIterator<'a, K: 'a, V: 'a> {
+  inner       : &'a SharedBuffer<K, V>,
+  spare       : &'a [usize],
+  ready       : &'a [usize],
+  next        : usize,
+}
+
+// capacity is the number of distinct keys the channel can hold
+pub fn channel<K, V>(capacity : usize) -> (Sender<K, V>, Receiver<K, V>)
+  where K : Hash + Eq + Clone + Send, V : Send
+{
+  let mut capacity = capacity;
+
+  // capacity cannot be zero, silently set to one
+  if capacity == 0 { capacity = 1; }
+
+  // a slot is announced once each time it turns fresh, and the reader
+  // takes it before it can turn fresh again, so the ring never overflows
+  let (ring, writer, reader) = CircularBuffer::new(capacity).into_parts();
+  let a = Arc::new(SharedBuffer::new(capacity, ring));
+  (Sender::new(a.clone(), writer), Receiver::new(a, reader))
+}
+
+impl <K, V> SharedBuffer<K, V> {
+  fn new(capacity : usize, ring : Ring<usize, Heap<usize>>) -> SharedBuffer<K, V> {
+    let slots = (0..capacity).map(|_i| Slot {
+      latest  : AtomicUsize::new(0),
+      data    : [UnsafeCell::new(None), UnsafeCell::new(None), UnsafeCell::new(None)],
+    }).collect();
+    SharedBuffer { slots, ring, }
+  }
+}
+
+impl <K, V> Sender<K, V>
+  where K : Hash + Eq + Clone + Send, V : Send
+{
+  fn new(inner: Arc<SharedBuffer<K, V>>, writer: Writer) -> Sender<K, V> {
+    let capacity = inner.slots.len();
+    Sender {
+      inner,
+      writer,
+      keys        : HashMap::with_capacity(capacity),
+      spare       : vec![1; capacity],
+      conflated   : 0,
+    }
+  }
+
+  // Replaces the unread value of the same key, if any. Only fails, with
+  // the key and value handed back, when a new key doesn't fit anymore.
+  pub fn put(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+    let capacity = self.inner.slots.len();
+    let idx = match self.keys.get(&key) {
+      Some(idx) => *idx,
+      None => {
+        if self.keys.len() == capacity {
+          return Err((key, value));
+        }
+        let idx = self.keys.len();
+        self.keys.insert(key.clone(), idx);
+        idx
+      }
+    };
+
+    let shared = &*self.inner;
+    let slot = &shared.slots[idx];
+    let pos = self.spare[idx];
+
+    // the spare element is ours, it may still hold an item replaced earlier
+    unsafe { *slot.data[pos].get() = Some((key, value)); }
+    let old : usize = slot.latest.swap(FRESH | pos, Ordering::AcqRel);
+    self.spare[idx] = old & !FRESH;
+
+    if old & FRESH != 0 {
+      self.conflated += 1;
+    } else {
+      self.writer.put(&shared.ring, |v| *v = Some(idx));
+    }
+    Ok(())
+  }
+
+  // number of unread items replaced by a newer one of the same key
+  #[inline(always)]
+  pub fn conflated(&self) -> usize {
+    self.conflated
+  }
+
+  #[inline(always)]
+  pub fn keys(&self) -> usize {
+    self.keys.len()
+  }
+}
+
+impl <K, V> Receiver<K, V>
+  where K : Send, V : Send
+{
+  fn new(inner: Arc<SharedBuffer<K, V>>, reader: Reader<Vec<usize>>) -> Receiver<K, V> {
+    let capacity = inner.slots.len();
+    Receiver {
+      inner,
+      reader,
+      spare       : vec![2; capacity],
+      ready       : Vec::with_capacity(capacity),
+    }
+  }
+
+  // the latest value of every key put since the last call, at most one
+  // per key, in the order the keys got their first unread value
+  pub fn iter(&mut self) -> ConflateIterator<'_, K, V> {
+    let shared = &*self.inner;
+    self.ready.clear();
+    for idx in self.reader.iter(&shared.ring) {
+      let old : usize = shared.slots[idx].latest.swap(self.spare[idx], Ordering::AcqRel);
+      self.spare[idx] = old & !FRESH;
+      if old & FRESH != 0 {
+        self.ready.push(idx);
+      }
+    }
+
+    ConflateIterator {
+      inner   : shared,
+      spare   : self.spare.as_slice(),
+      ready   : self.ready.as_slice(),
+      next    : 0,
+    }
+  }
+}
+
+impl <'a, K: 'a, V: 'a> Iterator for ConflateIterator<'a, K, V> {
+  type Item = (K, V);
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<(K, V)> {
+    while self.next < self.ready.len() {
+      let idx : usize = self.ready[self.next];
+      self.next += 1;
+      // the reader owns its spare element of every slot
+      let item = unsafe { (*self.inner.slots[idx].data[self.spare[idx]].get()).take() };
+      if item.is_some() {
+        return item;
+      }
+    }
+    None
+  }
+}
+
+#[cfg(all(test, not(loom)))]
+pub mod tests;
@@ -0,0 +1,67 @@
+use conflate;
+use std::collections::HashMap;
+use std::thread;
+
+#[test]
+fn latest_per_key() {
+  let (mut tx, mut rx) = conflate::channel(4);
+  tx.put("a", 1).unwrap();
+  tx.put("b", 1).unwrap();
+  tx.put("a", 2).unwrap();
+  tx.put("a", 3).unwrap();
+  assert_eq!(tx.conflated(), 2);
+  assert_eq!(rx.iter().collect::<Vec<(&str, i32)>>(), vec![("a", 3), ("b", 1)]);
+  assert_eq!(rx.iter().count(), 0);
+  tx.put("b", 2).unwrap();
+  assert_eq!(rx.iter().collect::<Vec<(&str, i32)>>(), vec![("b", 2)]);
+}
+
+#[test]
+fn hot_key_keeps_others() {
+  // a ring of 2 would have lost "b" long ago
+  let (mut tx, mut rx) = conflate::channel(2);
+  tx.put("b", 0).unwrap();
+  for i in 0..1000 {
+    tx.put("a", i).unwrap();
+  }
+  assert_eq!(rx.iter().collect::<Vec<(&str, i32)>>(), vec![("b", 0), ("a", 999)]);
+}
+
+#[test]
+fn capacity_exceeded() {
+  let (mut tx, _rx) = conflate::channel(2);
+  tx.put(1, "x").unwrap();
+  tx.put(2, "y").unwrap();
+  assert_eq!(tx.put(3, "z"), Err((3, "z")));
+  assert_eq!(tx.keys(), 2);
+  assert!(tx.put(1, "w").is_ok());
+}
+
+#[test]
+fn threaded_latest_wins() {
+  let (mut tx, mut rx) = conflate::channel(8);
+  let t = thread::spawn(move|| {
+    for i in 0..100_000usize {
+      tx.put(i % 8, i).unwrap();
+    }
+  });
+  let mut last = HashMap::new();
+  loop {
+    // once the writer is done, the next iter() gets the rest
+    let done = t.is_finished();
+    let mut got = 0;
+    for (k, v) in rx.iter() {
+      // values of a key only grow and belong to it
+      assert_eq!(v % 8, k);
+      if let Some(prev) = last.insert(k, v) {
+        assert!(prev < v);
+      }
+      got += 1;
+    }
+    if done && got == 0 { break; }
+  }
+  t.join().unwrap();
+  for k in 0..8 {
+    assert_eq!(last[&k], 100_000 - 8 + k);
+  }
+}
@@ -1,5 +1,33 @@
+// Without the std feature the crate is no_std. cb::fixed only needs core,
+// the Vec backed buffers and spsc need the alloc feature. Waiting for
+// items, mpsc, broadcast and conflate need std.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(any(feature = "std", test))]
+extern crate core;
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub mod cb;
+#[cfg(feature = "alloc")]
 pub mod spsc;
+#[cfg(feature = "std")]
+pub mod mpsc;
+#[cfg(feature = "std")]
+pub mod broadcast;
+#[cfg(feature = "std")]
+pub mod conflate;
+mod sync;
+
+#[cfg(loom)]
+extern crate loom;
+
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate futures_sink;
 
 #[cfg(test)]
 extern crate time;
+#[cfg(test)]
+extern crate futures;
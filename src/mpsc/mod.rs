@@ -0,0 +1,283 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::mem;
+use super::cb::{CircularBufferIterator, MIN_GEN_BITS, pos_bits};
+use super::sync::UnsafeCell;
+
+// flag value of a slot while a writer is filling it
+const BUSY : usize = !0;
+
+// each half of the seqno range has its own anchor
+const HALF : usize = 1 << (usize::BITS - 1);
+
+// The slot of a seqno is counted in laps and positions from the anchor of
+// its half of the seqno range. The first writer of a half sets the anchor
+// of the other one, half a range before anyone needs it. Unlike seqno /
+// size, the slots follow each other across the wraparound with any size.
+struct SharedBuffer<T> {
+  seqno       : AtomicUsize,              // the next ID to be claimed by a writer
+  anchors     : [AtomicUsize; 2],         // (lap << pos_bits) | position of each half's first seqno
+  data        : Vec<UnsafeCell<Option<T>>>, // 2*n preallocated elements
+  size        : usize,                    // n
+  pos_bits    : usize,                    // low bits of the flags holding a position
+  buffer      : Vec<AtomicUsize>,         // (generation << pos_bits) | position, or BUSY
+}
+
+pub struct Sender<T> {
+  inner: Arc<SharedBuffer<T>>,
+}
+
+unsafe impl<T: Send> Send for Sender<T> { }
+
+pub struct Receiver<T> {
+  inner       : Arc<SharedBuffer<T>>,
+  read_priv   : Vec<usize>,               // positions belong to the reader
+  max_read    : usize,                    // the next seqno the reader expects
+  read_slot   : usize,                    // and its lap and position
+  dropped     : usize,                    // seqnos the reader never got to see
+}
+
+unsafe impl<T: Send> Send for Receiver<T> { }
+
+pub fn channel<T: Send>(size : usize) -> (Sender<T>, Receiver<T>) {
+  from_buffer(SharedBuffer::with_seqno(size, 0))
+}
+
+fn from_buffer<T: Send>(buffer : SharedBuffer<T>) -> (Sender<T>, Receiver<T>) {
+  let a = Arc::new(buffer);
+  (Sender::new(a.clone()), Receiver::new(a))
+}
+
+impl <T> SharedBuffer<T> {
+  // starts the sequence numbers at seqno, tests use it to force a wraparound
+  fn with_seqno(size : usize, seqno : usize) -> SharedBuffer<T> {
+
+    let mut size = size;
+
+    // size cannot be zero, silently set to one
+    if size == 0 { size = 1; }
+
+    // the flags are tagged like the ones of the spsc ring, BUSY has all
+    // position bits set, which no position does
+    let bits = 8 * mem::size_of::<usize>();
+    let pos_bits = pos_bits(size);
+    if bits - pos_bits < MIN_GEN_BITS {
+      panic!("size: {} is too large, it leaves less than {} generation bits", size, MIN_GEN_BITS);
+    }
+
+    let mut data = Vec::with_capacity(2*size);
+    let mut buffer = Vec::with_capacity(size);
+
+    for i in 0..size {
+      buffer.push(AtomicUsize::new(i));
+      data.push(UnsafeCell::new(None));
+      data.push(UnsafeCell::new(None));
+    }
+
+    let ret = SharedBuffer {
+      seqno   : AtomicUsize::new(seqno),
+      anchors : [AtomicUsize::new(0), AtomicUsize::new(0)],
+      data,
+      size,
+      pos_bits,
+      buffer,
+    };
+
+    // the first seqno gets lap 0 and position 0, count back to its anchor
+    let back = seqno % HALF;
+    let anchor = match back % size {
+      0 => ret.flag(0usize.wrapping_sub(back / size), 0),
+      r => ret.flag(0usize.wrapping_sub(back / size + 1), size - r),
+    };
+    ret.anchors[seqno / HALF].store(anchor, Ordering::Relaxed);
+    ret.anchors[1 - seqno / HALF].store(ret.advance(anchor, HALF), Ordering::Relaxed);
+    ret
+  }
+
+  #[inline(always)]
+  fn flag(&self, gen: usize, pos: usize) -> usize {
+    (gen << self.pos_bits) | pos
+  }
+
+  #[inline(always)]
+  fn pos_of(&self, flag: usize) -> usize {
+    flag & ((1 << self.pos_bits) - 1)
+  }
+
+  // the generation of a slot is its lap around the ring, the initial
+  // flags carry generation 0 so they look older than the first lap
+  #[inline(always)]
+  fn gen_of(&self, slot: usize) -> usize {
+    (slot >> self.pos_bits).wrapping_add(1)
+  }
+
+  #[inline(always)]
+  fn slot_of(&self, seqno: usize) -> usize {
+    let anchor : usize = self.anchors[seqno / HALF].load(Ordering::Acquire);
+    self.advance(anchor, seqno % HALF)
+  }
+
+  // the slot n seqnos after the given one
+  #[inline(always)]
+  fn advance(&self, slot: usize, n: usize) -> usize {
+    let lap : usize = (slot >> self.pos_bits).wrapping_add(n / self.size);
+    let pos : usize = self.pos_of(slot) + n % self.size;
+    if pos < self.size {
+      self.flag(lap, pos)
+    } else {
+      self.flag(lap.wrapping_add(1), pos - self.size)
+    }
+  }
+
+  #[inline(always)]
+  fn step(&self, slot: usize) -> usize {
+    if self.pos_of(slot) + 1 == self.size {
+      self.flag((slot >> self.pos_bits).wrapping_add(1), 0)
+    } else {
+      slot + 1
+    }
+  }
+
+  // True if the flag belongs to an earlier lap than gen. The generations
+  // are truncated to the high bits of the flags, a writer has to stall
+  // for half of their range in laps before a newer one looks older.
+  #[inline(always)]
+  fn is_older(&self, flag: usize, gen: usize) -> bool {
+    let diff : usize = self.flag(gen, 0).wrapping_sub(self.flag(flag >> self.pos_bits, 0));
+    diff != 0 && diff <= 1 << (usize::BITS - 1)
+  }
+}
+
+impl<T: Send> Sender<T> {
+  fn new(inner: Arc<SharedBuffer<T>>) -> Sender<T> {
+    Sender { inner, }
+  }
+
+  // The setter receives the oldest item of the slot, which may not have been
+  // read yet. If another writer holds the slot or has already lapped it,
+  // the item is obsolete: the setter is not called, the seqno is lost and
+  // put() returns None.
+  #[inline(always)]
+  pub fn put<F>(&mut self, setter: F) -> Option<usize>
+      where F : FnMut(&mut Option<T>) {
+    // claim a seqno, this is the only point writers synchronize on
+    let shared = &*self.inner;
+    let seqno = shared.seqno.fetch_add(1, Ordering::AcqRel);
+    // the first writer of a half anchors the other one
+    if seqno.is_multiple_of(HALF) {
+      let next = shared.advance(shared.slot_of(seqno), HALF);
+      shared.anchors[1 - seqno / HALF].store(next, Ordering::Release);
+    }
+    if self.write(seqno, setter) { Some(seqno) } else { None }
+  }
+
+  // writes the item of a claimed seqno, unless a later lap has its slot
+  #[inline(always)]
+  fn write<F>(&mut self, seqno: usize, setter: F) -> bool
+      where F : FnMut(&mut Option<T>) {
+    let mut setter = setter;
+    let shared = &*self.inner;
+    let slot    = shared.slot_of(seqno);
+    let gen     = shared.gen_of(slot);
+    let flag    = &shared.buffer[shared.pos_of(slot)];
+
+    // take the slot over from the reader or from the previous lap
+    let mut old_flag : usize = flag.load(Ordering::Acquire);
+    loop {
+      if old_flag == BUSY || !shared.is_older(old_flag, gen) {
+        return false;
+      }
+      match flag.compare_exchange_weak(old_flag, BUSY, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => break,
+        Err(f) => old_flag = f,
+      }
+    }
+
+    // the BUSY flag makes old_pos ours until the store
+    let old_pos : usize = shared.pos_of(old_flag);
+    shared.data[old_pos].with_mut(|v| setter(unsafe { &mut *v }));
+    flag.store(shared.flag(gen, old_pos), Ordering::Release);
+    true
+  }
+
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.inner.seqno.load(Ordering::Acquire)
+  }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Sender<T> {
+    Sender { inner: self.inner.clone(), }
+  }
+}
+
+impl<T: Send> Receiver<T> {
+  fn new(inner: Arc<SharedBuffer<T>>) -> Receiver<T> {
+    let size = inner.size;
+    let max_read = inner.seqno.load(Ordering::Acquire);
+    Receiver {
+      read_priv : (size..2*size).collect(),
+      max_read,
+      read_slot : inner.slot_of(max_read),
+      dropped   : 0,
+      inner,
+    }
+  }
+
+  #[inline(always)]
+  pub fn iter(&mut self) -> CircularBufferIterator<'_, T> {
+    let shared = &*self.inner;
+    let size = shared.size;
+
+    // seqnos are claimed before they are written, so this is an upper bound
+    let seqno : usize = shared.seqno.load(Ordering::Acquire);
+    let skipped : usize = seqno.wrapping_sub(self.max_read).saturating_sub(size);
+    let start : usize = self.max_read.wrapping_add(skipped);
+    let mut slot : usize = shared.advance(self.read_slot, skipped);
+
+    // scan forward and stop at the first slot that is still being written
+    // or has been taken by a later lap already
+    let mut count : usize = 0;
+    let mut next : usize = start;
+    while next != seqno {
+      let gen = shared.gen_of(slot);
+      let flag = &shared.buffer[shared.pos_of(slot)];
+      let old_flag : usize = flag.load(Ordering::Acquire);
+      if old_flag == BUSY || old_flag >> shared.pos_bits != shared.flag(gen, 0) >> shared.pos_bits {
+        break;
+      }
+      let new_flag : usize = shared.flag(gen, self.read_priv[count]);
+      if flag.compare_exchange(old_flag, new_flag, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        break;
+      }
+      self.read_priv[count] = shared.pos_of(old_flag);
+      count += 1;
+      next = next.wrapping_add(1);
+      slot = shared.step(slot);
+    }
+
+    self.max_read = next;
+    self.read_slot = slot;
+    self.dropped += skipped;
+
+    // the iterator expects the newest item first
+    self.read_priv[..count].reverse();
+
+    CircularBufferIterator::new(shared.data.as_slice(), self.read_priv.as_slice(), start, count, skipped)
+  }
+
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.inner.seqno.load(Ordering::Acquire)
+  }
+
+  // total number of items the receiver missed so far
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.dropped
+  }
+}
+
+#[cfg(all(test, not(loom)))]
+pub mod tests;
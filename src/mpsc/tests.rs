@@ -0,0 +1,176 @@
+use mpsc;
+use std::thread;
+use std::sync::atomic::Ordering;
+use std::collections::HashSet;
+use cb::IterRange;
+
+#[test]
+fn single_sender() {
+  let (mut tx, mut rx) = mpsc::channel(4);
+  assert_eq!(tx.put(|v| *v = Some(1)), Some(0));
+  assert_eq!(tx.put(|v| *v = Some(2)), Some(1));
+  assert_eq!(tx.put(|v| *v = Some(3)), Some(2));
+  {
+    let i = rx.iter();
+    assert_eq!(i.get_range(), (0, 3));
+    assert_eq!(i.skipped(), 0);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![1, 2, 3]);
+  }
+  assert_eq!(rx.iter().count(), 0);
+}
+
+#[test]
+fn overwrite_oldest() {
+  let (mut tx, mut rx) = mpsc::channel(3);
+  for i in 0..5 {
+    tx.put(|v| {
+      if i >= 3 {
+        // the setter sees the oldest unread item
+        assert_eq!(*v, Some(i-3));
+      }
+      *v = Some(i);
+    });
+  }
+  {
+    let i = rx.iter();
+    assert_eq!(i.get_range(), (2, 5));
+    assert_eq!(i.skipped(), 2);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![2, 3, 4]);
+  }
+  assert_eq!(rx.dropped(), 2);
+  assert_eq!(rx.seqno(), 5);
+}
+
+#[test]
+fn read_twice() {
+  let (mut tx, mut rx) = mpsc::channel(2);
+  tx.put(|v| *v = Some(1));
+  assert_eq!(rx.iter().count(), 1);
+  assert_eq!(rx.iter().count(), 0);
+  tx.put(|v| *v = Some(2));
+  tx.put(|v| *v = Some(3));
+  assert_eq!(rx.iter().sum::<i32>(), 5);
+  assert_eq!(rx.iter().count(), 0);
+}
+
+#[test]
+fn stalled_writer() {
+  // a writer stalled between claiming its seqno and taking the slot over
+  // comes back after nine laps, it must not overwrite the newer items
+  let (mut tx, mut rx) = mpsc::channel::<usize>(2);
+  let mut stalled = tx.clone();
+  let seqno = stalled.inner.seqno.fetch_add(1, Ordering::AcqRel);
+  for i in 1..20 {
+    tx.put(|v| *v = Some(i));
+  }
+  let mut called = false;
+  assert!(!stalled.write(seqno, |v| { called = true; *v = Some(seqno); }));
+  assert!(!called);
+  {
+    let i = rx.iter();
+    assert_eq!(i.get_range(), (18, 20));
+    assert_eq!(i.collect::<Vec<usize>>(), vec![18, 19]);
+  }
+  tx.put(|v| *v = Some(20));
+  assert_eq!(rx.iter().collect::<Vec<usize>>(), vec![20]);
+}
+
+#[test]
+fn seqno_turnover() {
+  // three slots don't divide the seqno range, they still have to follow
+  // each other across the wraparound
+  let (mut tx, mut rx) = mpsc::from_buffer(mpsc::SharedBuffer::with_seqno(3, usize::MAX-4));
+  for i in 0..3 {
+    tx.put(|v| *v = Some(i));
+  }
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 1, 2]);
+  assert_eq!(tx.put(|v| *v = Some(3)), Some(usize::MAX-1));
+  assert_eq!(tx.put(|v| *v = Some(4)), Some(usize::MAX));
+  assert_eq!(tx.put(|v| *v = Some(5)), Some(0));
+  assert_eq!(tx.put(|v| *v = Some(6)), Some(1));
+  {
+    let i = rx.iter();
+    assert_eq!(i.get_range(), (usize::MAX, 2));
+    assert_eq!(i.skipped(), 1);
+    assert_eq!(i.collect::<Vec<i32>>(), vec![4, 5, 6]);
+  }
+  tx.put(|v| *v = Some(7));
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![7]);
+  assert_eq!(rx.dropped(), 1);
+}
+
+#[test]
+fn seqno_halves() {
+  // the second half of the seqno range goes by the anchor the first
+  // writer of the first half left
+  let (mut tx, mut rx) = mpsc::channel::<usize>(3);
+  tx.put(|v| *v = Some(0));
+  assert_eq!(rx.iter().collect::<Vec<usize>>(), vec![0]);
+  let half = 1 << (usize::BITS - 1);
+  tx.inner.seqno.store(half-1, Ordering::Release);
+  for i in 0..3 {
+    tx.put(|v| *v = Some(half-1+i));
+  }
+  {
+    let i = rx.iter();
+    assert_eq!(i.skipped(), half-2);
+    assert_eq!(i.collect::<Vec<usize>>(), vec![half-1, half, half+1]);
+  }
+}
+
+#[test]
+fn cloned_senders() {
+  // with enough space nothing is lost even if the senders run
+  // in parallel
+  let (tx, mut rx) = mpsc::channel::<usize>(4_000);
+  let mut threads = Vec::new();
+  for t in 0..4usize {
+    let mut tx = tx.clone();
+    threads.push(thread::spawn(move|| {
+      for i in 0..1_000usize {
+        tx.put(|v| *v = Some(t*1_000+i));
+      }
+    }));
+  }
+  for t in threads {
+    t.join().unwrap();
+  }
+  let recvd : HashSet<usize> = rx.iter().collect();
+  assert_eq!(recvd.len(), 4_000);
+  assert_eq!(tx.seqno(), 4_000);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn concurrent_read() {
+  // items of a given sender must arrive in order and only once
+  let (tx, mut rx) = mpsc::channel::<(usize, usize)>(16);
+  let mut threads = Vec::new();
+  for t in 0..4usize {
+    let mut tx = tx.clone();
+    threads.push(thread::spawn(move|| {
+      for i in 0..10_000usize {
+        tx.put(|v| *v = Some((t, i)));
+      }
+    }));
+  }
+  drop(tx);
+  let mut last : [Option<usize>; 4] = [None; 4];
+  let mut recvd = 0usize;
+  loop {
+    let done = threads.iter().all(|t| t.is_finished());
+    for (t, i) in rx.iter() {
+      if let Some(prev) = last[t] {
+        assert!(prev < i);
+      }
+      last[t] = Some(i);
+      recvd += 1;
+    }
+    if done { break; }
+  }
+  for t in threads {
+    t.join().unwrap();
+  }
+  assert!(recvd > 0);
+  assert!(recvd + rx.dropped() <= 40_000);
+}
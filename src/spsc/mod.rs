@@ -1,64 +1,336 @@
 pub mod noloss;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(feature = "std")]
+mod notify;
 
-use std::cell::UnsafeCell;
-use std::sync::Arc;
-use super::cb::{CircularBuffer, CircularBufferIterator};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+use super::cb::{CircularBuffer, CircularBufferIterator, PartialIterator, PeekIterator, Policy, Heap, Ring, Writer, Reader};
+#[cfg(feature = "std")]
+use super::cb::FreshIterator;
+#[cfg(feature = "std")]
+use self::notify::Notify;
 
-pub struct Sender<T> {
-  inner: Arc<UnsafeCell<CircularBuffer<T>>>,
+struct Shared<T> {
+  ring            : Ring<T, Heap<T>>,
+  #[cfg(feature = "std")]
+  notify          : Notify,
+  sender_alive    : AtomicBool,
+  receiver_alive  : AtomicBool,
 }
 
-unsafe impl<T> Send for Sender<T> { }
+// each handle owns its side of the buffer and shares only the ring,
+// so both are Send exactly when T is
+pub struct Sender<T> {
+  inner   : Arc<Shared<T>>,
+  writer  : Writer,
+}
 
 pub struct Receiver<T> {
-  inner: Arc<UnsafeCell<CircularBuffer<T>>>,
+  inner   : Arc<Shared<T>>,
+  reader  : Reader<Vec<usize>>,
 }
 
-unsafe impl<T> Send for Receiver<T> { }
+// Watches the channel from aside: it clones the items in the ring but
+// leaves them to the receiver. Any number of them can be cloned off one.
+pub struct Peeker<T> {
+  inner   : Arc<Shared<T>>,
+}
+
+// returned by try_iter() once the sender is gone and everything is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
 
 pub fn channel<T: Send>(size : usize) -> (Sender<T>, Receiver<T>) {
-    let a = Arc::new(UnsafeCell::new(CircularBuffer::new(size)));
-    (Sender::new(a.clone()), Receiver::new(a))
+    channel_with(size, Policy::OverwriteOldest)
+}
+
+// the policy decides what put() does when the reader is size items behind
+pub fn channel_with<T: Send>(size : usize, policy : Policy) -> (Sender<T>, Receiver<T>) {
+    from_buffer(CircularBuffer::with_policy(size, policy))
+}
+
+// every put records when it happened, see Receiver::iter_fresh()
+#[cfg(feature = "std")]
+pub fn channel_timestamped<T: Send>(size : usize) -> (Sender<T>, Receiver<T>) {
+    from_buffer(CircularBuffer::timestamped(size))
+}
+
+fn from_buffer<T: Send>(buffer : CircularBuffer<T>) -> (Sender<T>, Receiver<T>) {
+    let (ring, writer, reader) = buffer.peekable().into_parts();
+    let a = Arc::new(Shared {
+      ring,
+      #[cfg(feature = "std")]
+      notify          : Notify::new(),
+      sender_alive    : AtomicBool::new(true),
+      receiver_alive  : AtomicBool::new(true),
+    });
+    (Sender::new(a.clone(), writer), Receiver::new(a, reader))
 }
 
 impl<T: Send> Sender<T> {
-  fn new(inner: Arc<UnsafeCell<CircularBuffer<T>>>) -> Sender<T> {
-    Sender { inner: inner, }
+  fn new(inner: Arc<Shared<T>>, writer: Writer) -> Sender<T> {
+    Sender { inner, writer, }
   }
 
+  // returns the seqno of the item, see CircularBuffer::put() for rejected ones
   #[inline(always)]
   pub fn put<F>(&mut self, setter: F) -> usize
       where F : FnMut(&mut Option<T>) {
-    unsafe { (*self.inner.get()).put(setter) }
+    self.try_put(setter).unwrap_or_else(|| self.seqno())
+  }
+
+  // None if the policy rejected the item, the reader is not woken then
+  #[inline(always)]
+  pub fn try_put<F>(&mut self, setter: F) -> Option<usize>
+      where F : FnMut(&mut Option<T>) {
+    let ret = self.writer.put(&self.inner.ring, setter);
+    #[cfg(feature = "std")]
+    if ret.is_some() {
+      self.inner.notify.notify();
+    }
+    ret
+  }
+
+  // puts n items, the reader sees them all at once. returns the first
+  // seqno and the number of items the policy let in.
+  #[inline(always)]
+  pub fn put_batch<F>(&mut self, n: usize, setter: F) -> (usize, usize)
+      where F : FnMut(usize, &mut Option<T>) {
+    let ret = self.writer.put_batch(&self.inner.ring, n, setter);
+    #[cfg(feature = "std")]
+    if ret.1 > 0 {
+      self.inner.notify.notify();
+    }
+    ret
+  }
+
+  #[inline(always)]
+  pub fn extend<I>(&mut self, items: I) -> usize
+      where I : IntoIterator<Item=T> {
+    let ret = self.writer.extend(&self.inner.ring, items);
+    #[cfg(feature = "std")]
+    self.inner.notify.notify();
+    ret
+  }
+
+  // see Writer::extend_displacing(), returns the first seqno and the count
+  pub(crate) fn extend_displacing<I, D>(&mut self, items: I, displaced: D) -> (usize, usize)
+      where I : IntoIterator<Item=T>, D : FnMut(Option<usize>, T) {
+    let ret = self.writer.extend_displacing(&self.inner.ring, items, displaced);
+    #[cfg(feature = "std")]
+    self.inner.notify.notify();
+    ret
+  }
+
+  // puts value unless the policy rejects it, then it is returned
+  #[inline(always)]
+  pub fn send(&mut self, value: T) -> Result<usize, T> {
+    let ret = self.writer.send(&self.inner.ring, value);
+    #[cfg(feature = "std")]
+    if ret.is_ok() {
+      self.inner.notify.notify();
+    }
+    ret
+  }
+
+  // number of items the policy kept out so far
+  #[inline(always)]
+  pub fn rejected(&self) -> usize {
+    self.writer.rejected()
+  }
+
+  // Number of items that can be put before the oldest unread one gets
+  // overwritten. The reader only reports its position when it iterates,
+  // so there may be more room than this.
+  #[inline(always)]
+  pub fn room(&self) -> usize {
+    self.writer.room(&self.inner.ring)
   }
 
   #[inline(always)]
   pub fn tmp<F>(&mut self, setter: F)
       where F : FnMut(&mut Option<T>){
-    unsafe { (*self.inner.get()).tmp(setter) }
+    self.writer.tmp(&self.inner.ring, setter)
+  }
+
+  // the seqno of the item tmp() hands out, if there is one
+  #[inline(always)]
+  pub(crate) fn tmp_seqno(&self) -> usize {
+    self.writer.tmp_seqno(&self.inner.ring)
   }
 
   #[inline(always)]
   pub fn seqno(&self) -> usize{
-    unsafe { (*self.inner.get()).seqno() }
+    self.inner.ring.seqno()
+  }
+
+  // false once the receiver is dropped, nobody will read what is put
+  #[inline(always)]
+  pub fn receiver_alive(&self) -> bool {
+    self.inner.receiver_alive.load(Ordering::Acquire)
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    self.inner.sender_alive.store(false, Ordering::SeqCst);
+    #[cfg(feature = "std")]
+    self.inner.notify.notify();
   }
 }
 
 impl<T: Send> Receiver<T> {
-  fn new(inner: Arc<UnsafeCell<CircularBuffer<T>>>) -> Receiver<T> {
-    Receiver { inner: inner, }
+  fn new(inner: Arc<Shared<T>>, reader: Reader<Vec<usize>>) -> Receiver<T> {
+    Receiver { inner, reader, }
+  }
+
+  #[inline(always)]
+  pub fn iter(&mut self) -> CircularBufferIterator<'_, T> {
+    self.reader.iter(&self.inner.ring)
+  }
+
+  // only the items actually returned by next() count as read
+  #[inline(always)]
+  pub fn iter_partial(&mut self) -> PartialIterator<'_, T> {
+    self.reader.iter_partial(&self.inner.ring)
+  }
+
+  // Items put more than max_age ago are consumed and counted, but not
+  // returned. The others come with their age. Needs channel_timestamped(),
+  // otherwise every item counts as fresh.
+  #[cfg(feature = "std")]
+  #[inline(always)]
+  pub fn iter_fresh(&mut self, max_age: Duration) -> FreshIterator<'_, T> {
+    self.reader.iter_fresh(&self.inner.ring, max_age)
+  }
+
+  // total number of items iter_fresh() skipped as too old
+  #[cfg(feature = "std")]
+  #[inline(always)]
+  pub fn expired(&self) -> usize {
+    self.reader.expired()
+  }
+
+  // Looks at the readable items without consuming them, the next
+  // iter() still returns them unless the writer laps them first. It is
+  // for the consumer itself, another thread watches through a peeker().
+  #[inline(always)]
+  pub fn peek_iter(&mut self) -> PeekIterator<'_, T> {
+    self.reader.peek_iter(&self.inner.ring)
+  }
+
+  #[inline(always)]
+  pub fn peek_latest(&mut self) -> Option<&T> {
+    self.reader.peek_latest(&self.inner.ring)
+  }
+
+  // a handle for another thread to look at the items, see Peeker
+  pub fn peeker(&self) -> Peeker<T>
+      where T : Sync + Clone {
+    Peeker { inner: self.inner.clone(), }
+  }
+
+  // Ok with the new items, possibly none, while the sender is alive or
+  // there is something left to read. Err once it is gone and drained.
+  pub fn try_iter(&mut self) -> Result<CircularBufferIterator<'_, T>, Disconnected> {
+    if self.is_drained() {
+      Err(Disconnected)
+    } else {
+      Ok(self.iter())
+    }
+  }
+
+  // parks the reader until there is something new to read or the sender is gone
+  #[cfg(feature = "std")]
+  pub fn wait_iter(&mut self) -> CircularBufferIterator<'_, T> {
+    self.wait(None);
+    self.iter()
+  }
+
+  // like wait_iter(), but the iterator may be empty if the timeout expires
+  #[cfg(feature = "std")]
+  pub fn wait_iter_timeout(&mut self, timeout: Duration) -> CircularBufferIterator<'_, T> {
+    self.wait(Some(Instant::now() + timeout));
+    self.iter()
+  }
+
+  #[cfg(feature = "std")]
+  fn wait(&self, deadline: Option<Instant>) {
+    self.inner.notify.wait(|| self.is_ready(), deadline);
   }
 
+  // there is something to read or the sender is gone
+  #[cfg(feature = "std")]
   #[inline(always)]
-  pub fn iter(&mut self) -> CircularBufferIterator<T> {
-    unsafe { (*self.inner.get()).iter() }
+  fn is_ready(&self) -> bool {
+    self.is_disconnected() || self.reader.has_news(&self.inner.ring)
+  }
+
+  // the sender is gone, though there may be items left to read
+  #[inline(always)]
+  pub fn is_disconnected(&self) -> bool {
+    !self.inner.sender_alive.load(Ordering::Acquire)
+  }
+
+  // check the flag first, so all puts of a dropped sender are visible
+  #[inline(always)]
+  fn is_drained(&self) -> bool {
+    self.is_disconnected() && !self.reader.has_news(&self.inner.ring)
+  }
+
+  // total number of items the receiver missed so far
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.reader.dropped()
   }
 
   #[inline(always)]
   pub fn seqno(&self) -> usize{
-    unsafe { (*self.inner.get()).seqno() }
+    self.inner.ring.seqno()
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    self.inner.receiver_alive.store(false, Ordering::Release);
+  }
+}
+
+impl<T: Send + Sync + Clone> Peeker<T> {
+  // Clones of the items neither read nor fetched by the receiver yet,
+  // oldest first. The items the receiver fetched by its own peek_iter()
+  // or a partial read are with the receiver, the peeker doesn't see them.
+  pub fn peek_iter(&self) -> alloc::vec::IntoIter<T> {
+    let mut items = self.inner.ring.peek(usize::MAX);
+    items.reverse();
+    items.into_iter()
+  }
+
+  // a clone of the newest item still in the ring
+  pub fn peek_latest(&self) -> Option<T> {
+    self.inner.ring.peek(1).pop()
+  }
+
+  #[inline(always)]
+  pub fn seqno(&self) -> usize {
+    self.inner.ring.seqno()
+  }
+}
+
+impl<T> Clone for Peeker<T> {
+  fn clone(&self) -> Peeker<T> {
+    Peeker { inner: self.inner.clone(), }
   }
 }
 
-#[cfg(test)]
+// the tests are older than these lints
+#[cfg(all(test, feature = "std", not(loom)))]
+#[allow(clippy::unnecessary_cast, clippy::bool_assert_comparison, clippy::unnecessary_fold,
+        clippy::single_match, clippy::match_ref_pats, clippy::bool_comparison,
+        clippy::needless_borrow, clippy::len_zero)]
 pub mod tests;
@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use super::Overflow;
+
+// Turns items into bytes and back for the spill files.
+pub trait Codec<T> {
+  fn encode(&mut self, value: &T, out: &mut Vec<u8>) -> io::Result<()>;
+  fn decode(&mut self, bytes: &[u8]) -> io::Result<T>;
+}
+
+// stores byte vectors as they are
+pub struct Bytes;
+
+impl Codec<Vec<u8>> for Bytes {
+  fn encode(&mut self, value: &Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+    out.extend_from_slice(value);
+    Ok(())
+  }
+
+  fn decode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    Ok(bytes.to_vec())
+  }
+}
+
+// The segments of a spill directory go through three names:
+//   <index>.open    being written by DiskSpill
+//   <index>.seg     sealed, waiting for a Replay
+//   <index>.replay  claimed by a Replay, removed once replayed
+// A rename is atomic, so the two sides never read a half written file
+// and never both own the same one.
+const OPEN    : &str = "open";
+const SEALED  : &str = "seg";
+const CLAIMED : &str = "replay";
+
+fn segment_path(dir: &Path, index: u64, ext: &str) -> PathBuf {
+  dir.join(format!("{:020}.{}", index, ext))
+}
+
+// the index and extension of a segment file, None for anything else
+fn parse_segment(path: &Path) -> Option<(u64, String)> {
+  let index = path.file_stem()?.to_str()?.parse().ok()?;
+  let ext = path.extension()?.to_str()?;
+  Some((index, ext.to_string()))
+}
+
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, String)>> {
+  let mut segments = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    if let Some(segment) = parse_segment(&entry?.path()) {
+      segments.push(segment);
+    }
+  }
+  segments.sort();
+  Ok(segments)
+}
+
+struct Segment {
+  index       : u64,
+  bytes       : usize,
+  items       : usize,
+}
+
+// An Overflow that appends the displaced items to segment files in dir,
+// each record is a little endian u32 length and the encoded item. A
+// segment is sealed once it would grow over segment_size, and when all
+// of them take more than max_bytes, the oldest sealed ones are removed
+// with their items. Segments left over from an earlier run are kept for
+// the replay, but the caps only cover the ones written by this spill.
+//
+// A write or flush error seals the open segment as it is, with a torn
+// record at its end at worst, and the next item starts a new one. An
+// index is never written twice, so nothing already on disk is truncated.
+pub struct DiskSpill<T, C> {
+  dir           : PathBuf,
+  codec         : C,
+  segment_size  : usize,
+  max_bytes     : usize,
+  file          : Option<BufWriter<File>>,  // the open segment, if any
+  index         : u64,                      // of the open segment
+  bytes         : usize,                    // written to the open segment
+  items         : usize,
+  sealed        : VecDeque<Segment>,        // oldest first
+  buf           : Vec<u8>,
+  spilled       : usize,
+  dropped       : usize,
+  errors        : usize,
+  _item         : PhantomData<fn(T)>,
+}
+
+impl<T, C: Codec<T>> DiskSpill<T, C> {
+  pub fn create<P: AsRef<Path>>(dir: P, codec: C, segment_size: usize, max_bytes: usize)
+      -> io::Result<DiskSpill<T, C>> {
+    let dir = dir.as_ref().to_path_buf();
+    fs::create_dir_all(&dir)?;
+
+    // a crashed writer left its open segment behind, it is complete up
+    // to its last full record
+    let mut next = 0;
+    for (index, ext) in list_segments(&dir)? {
+      if ext == OPEN {
+        fs::rename(segment_path(&dir, index, OPEN), segment_path(&dir, index, SEALED))?;
+      }
+      next = next.max(index + 1);
+    }
+
+    Ok(DiskSpill {
+      dir,
+      codec,
+      segment_size  : segment_size.min(max_bytes),
+      max_bytes,
+      file          : None,
+      index         : next,
+      bytes         : 0,
+      items         : 0,
+      sealed        : VecDeque::new(),
+      buf           : Vec::new(),
+      spilled       : 0,
+      dropped       : 0,
+      errors        : 0,
+      _item         : PhantomData,
+    })
+  }
+
+  // number of items written to disk
+  #[inline(always)]
+  pub fn spilled(&self) -> usize {
+    self.spilled
+  }
+
+  // number of items removed unreplayed to stay within max_bytes
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.dropped
+  }
+
+  // Number of items lost because they could not be encoded or written. A
+  // failed flush may also cost the items still buffered, they are not
+  // counted here.
+  #[inline(always)]
+  pub fn errors(&self) -> usize {
+    self.errors
+  }
+
+  fn write(&mut self, value: &T) -> io::Result<()> {
+    self.buf.clear();
+    self.codec.encode(value, &mut self.buf)?;
+    let len = u32::try_from(self.buf.len())
+      .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "item too large for a spill record"))?;
+    let record = 4 + self.buf.len();
+
+    if self.bytes > 0 && self.bytes + record > self.segment_size {
+      self.seal()?;
+    }
+    if self.file.is_none() {
+      self.file = Some(BufWriter::new(self.create_segment()?));
+    }
+    let buf = &self.buf;
+    let written = match self.file {
+      Some(ref mut file) => file.write_all(&len.to_le_bytes()).and_then(|()| file.write_all(buf)),
+      None => Ok(()),
+    };
+    if let Err(e) = written {
+      // the record may be torn, nothing goes behind it
+      let _ = self.seal();
+      return Err(e);
+    }
+    self.bytes += record;
+    self.items += 1;
+    self.spilled += 1;
+    self.enforce_cap()
+  }
+
+  fn enforce_cap(&mut self) -> io::Result<()> {
+    let mut total : usize = self.bytes + self.sealed.iter().map(|s| s.bytes).sum::<usize>();
+    while total > self.max_bytes {
+      let segment = match self.sealed.pop_front() {
+        Some(segment) => segment,
+        None => break,
+      };
+      total -= segment.bytes;
+      match fs::remove_file(segment_path(&self.dir, segment.index, SEALED)) {
+        Ok(()) => self.dropped += segment.items,
+        // a Replay claimed it already
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<T: Send, C: Codec<T>> Overflow for DiskSpill<T, C> {
+  type Input = T;
+  fn overflow(&mut self, val : &mut Option<T>) {
+    if let Some(v) = val.take() {
+      if self.write(&v).is_err() {
+        self.errors += 1;
+      }
+    }
+  }
+}
+
+impl<T, C> DiskSpill<T, C> {
+  // Closes the open segment, so a Replay sees everything spilled so far.
+  // Dropping the spill does the same. Even if it fails, the segment is
+  // done with and the next item goes to a new one.
+  pub fn seal(&mut self) -> io::Result<()> {
+    let mut file = match self.file.take() {
+      Some(file) => file,
+      None => return Ok(()),
+    };
+    let segment = Segment { index: self.index, bytes: self.bytes, items: self.items };
+    self.index += 1;
+    self.bytes = 0;
+    self.items = 0;
+
+    // after a failed flush the rest of the buffer is dropped, not retried
+    let flushed = file.flush();
+    drop(file.into_parts());
+    fs::rename(segment_path(&self.dir, segment.index, OPEN),
+               segment_path(&self.dir, segment.index, SEALED))?;
+    self.sealed.push_back(segment);
+    flushed
+  }
+
+  // creates the open segment at the first index no file has
+  fn create_segment(&mut self) -> io::Result<File> {
+    loop {
+      let taken = [SEALED, CLAIMED].iter().any(|ext| segment_path(&self.dir, self.index, ext).exists());
+      if !taken {
+        let path = segment_path(&self.dir, self.index, OPEN);
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+          Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+          result => return result,
+        }
+      }
+      self.index += 1;
+    }
+  }
+}
+
+impl<T, C> Drop for DiskSpill<T, C> {
+  fn drop(&mut self) {
+    let _ = self.seal();
+  }
+}
+
+// Reads back the sealed segments of a spill directory, oldest first, and
+// removes each once it is replayed. It can run on the reader's side while
+// the spill keeps writing, then the iterator ends at the last sealed
+// segment and picks up the newer ones on the next call.
+pub struct Replay<T, C> {
+  dir       : PathBuf,
+  codec     : C,
+  index     : Option<u64>,      // the claimed segment
+  data      : Vec<u8>,
+  pos       : usize,
+  _item     : PhantomData<fn() -> T>,
+}
+
+impl<T, C: Codec<T>> Replay<T, C> {
+  pub fn open<P: AsRef<Path>>(dir: P, codec: C) -> Replay<T, C> {
+    Replay {
+      dir     : dir.as_ref().to_path_buf(),
+      codec,
+      index   : None,
+      data    : Vec::new(),
+      pos     : 0,
+      _item   : PhantomData,
+    }
+  }
+
+  // a segment claimed earlier but not finished comes first, it is
+  // replayed again from its beginning
+  fn claim(&mut self) -> io::Result<bool> {
+    for (index, ext) in list_segments(&self.dir)? {
+      let path = segment_path(&self.dir, index, CLAIMED);
+      if ext == SEALED {
+        match fs::rename(segment_path(&self.dir, index, SEALED), &path) {
+          Ok(()) => (),
+          // removed by the spill's cap meanwhile
+          Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+          Err(e) => return Err(e),
+        }
+      } else if ext != CLAIMED {
+        continue;
+      }
+      self.data.clear();
+      File::open(&path)?.read_to_end(&mut self.data)?;
+      self.index = Some(index);
+      self.pos = 0;
+      return Ok(true);
+    }
+    Ok(false)
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    if let Some(index) = self.index.take() {
+      fs::remove_file(segment_path(&self.dir, index, CLAIMED))?;
+    }
+    Ok(())
+  }
+
+  fn record(&mut self) -> io::Result<T> {
+    let rest = &self.data[self.pos..];
+    if rest.len() >= 4 {
+      let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+      if rest.len() >= 4 + len {
+        self.pos += 4 + len;
+        return self.codec.decode(&rest[4..4+len]);
+      }
+    }
+    // a torn record at the end of a crashed writer's segment
+    self.pos = self.data.len();
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated spill record"))
+  }
+}
+
+impl<T, C: Codec<T>> Iterator for Replay<T, C> {
+  type Item = io::Result<T>;
+
+  fn next(&mut self) -> Option<io::Result<T>> {
+    loop {
+      if self.index.is_some() && self.pos < self.data.len() {
+        return Some(self.record());
+      }
+      if let Err(e) = self.finish() {
+        return Some(Err(e));
+      }
+      match self.claim() {
+        Ok(true) => (),
+        Ok(false) => return None,
+        Err(e) => return Some(Err(e)),
+      }
+    }
+  }
+}
@@ -0,0 +1,420 @@
+#[cfg(feature = "std")]
+pub mod disk;
+#[cfg(feature = "std")]
+pub mod ordered;
+
+use super::{Sender, Receiver};
+use alloc::collections::VecDeque;
+use alloc::collections::vec_deque::Drain;
+use core::cell::Cell;
+use core::iter;
+use core::marker::PhantomData;
+use core::mem;
+
+pub trait Overflow {
+  type Input : Send;
+  fn overflow(&mut self, val : &mut Option<Self::Input>);
+
+  // like overflow(), with the seqno the item got when it was put. pour()
+  // passes the items it displaced through here.
+  fn overflow_at(&mut self, _seqno : usize, val : &mut Option<Self::Input>) {
+    self.overflow(val)
+  }
+
+  // number of items waiting to be put back into the channel
+  fn backlog(&self) -> usize { 0 }
+
+  // the oldest item of the backlog, pour() puts it before the new one
+  fn retry(&mut self) -> Option<Self::Input> { None }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PourResult {
+  // the value went into the channel with this seqno
+  Poured(usize),
+  // the same, but an unread item had to go to the overflow
+  Overflowed(usize),
+  // the value itself went to the overflow, behind the backlog
+  Deferred,
+  // the channel's policy kept the value out, it went to the overflow
+  Rejected,
+}
+
+impl PourResult {
+  // the seqno of the value, if it went into the channel
+  #[inline(always)]
+  pub fn seqno(&self) -> Option<usize> {
+    match *self {
+      PourResult::Poured(seqno) | PourResult::Overflowed(seqno) => Some(seqno),
+      PourResult::Deferred | PourResult::Rejected => None,
+    }
+  }
+}
+
+// what a pour_all() call did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PourSummary {
+  pub poured        : usize,          // values that went into the channel
+  pub overflowed    : usize,          // items handed to the overflow, displaced or deferred
+  pub first_seqno   : Option<usize>,  // of the poured values, they follow each other
+  pub last_seqno    : Option<usize>,
+}
+
+pub fn pour<T: Send>(value: &mut Option<T>,
+                     destination: &mut Sender<T>,
+                     overflow: &mut dyn Overflow<Input=T>)
+    -> PourResult {
+  //
+  let retried = overflow.backlog() > 0;
+  let displaced = retry_backlog(destination, overflow);
+
+  // if some of it is still waiting, the new value waits behind it. so it
+  // does if the backlog took all the room, it would displace a retried item.
+  if overflow.backlog() > 0 || (retried && destination.room() == 0) {
+    overflow.overflow(value);
+    return PourResult::Deferred;
+  }
+
+  match put_one(value, destination, overflow) {
+    PourResult::Poured(seqno) if displaced > 0 => PourResult::Overflowed(seqno),
+    result => result,
+  }
+}
+
+// Pours all items with a single publish, the reader sees them at once.
+// Cheaper than calling pour() for each of them, and the summary keeps
+// the count of what went where. The items end up where pour() would put
+// them one by one: behind a backlog they wait in the overflow as well.
+pub fn pour_all<T: Send, I>(items: I,
+                            destination: &mut Sender<T>,
+                            overflow: &mut dyn Overflow<Input=T>)
+    -> PourSummary
+  where I : IntoIterator<Item=T>
+{
+  let retried = overflow.backlog() > 0;
+  let mut summary = PourSummary { overflowed: retry_backlog(destination, overflow), ..Default::default() };
+
+  if overflow.backlog() > 0 {
+    for item in items {
+      overflow.overflow(&mut Some(item));
+      summary.overflowed += 1;
+    }
+    return summary;
+  }
+
+  // after a backlog only as many items go in as there is room left, the
+  // rest would displace the retried items
+  let mut items = items.into_iter();
+  let limit : usize = if retried { destination.room() } else { usize::MAX };
+
+  // an item going to the overflow may start a backlog, then the rest
+  // queues up behind it as it would with pour()
+  let deferring = Cell::new(false);
+  let mut overflowed : usize = 0;
+  let (first, count) = {
+    let mut taken = items.by_ref().take(limit);
+    let next = iter::from_fn(|| if deferring.get() { None } else { taken.next() });
+    destination.extend_displacing(next, |seqno, item| {
+      let mut item = Some(item);
+      match seqno {
+        Some(seqno) => overflow.overflow_at(seqno, &mut item),
+        None => overflow.overflow(&mut item),
+      }
+      overflowed += 1;
+      if overflow.backlog() > 0 { deferring.set(true); }
+    })
+  };
+  summary.overflowed += overflowed;
+
+  for item in items {
+    overflow.overflow(&mut Some(item));
+    summary.overflowed += 1;
+  }
+
+  if count > 0 {
+    summary.poured = count;
+    summary.first_seqno = Some(first);
+    summary.last_seqno = Some(first.wrapping_add(count - 1));
+  }
+  summary
+}
+
+// puts the backlog first, as far as the reader has room for it. returns
+// the number of items that went to the overflow meanwhile.
+fn retry_backlog<T: Send>(destination: &mut Sender<T>,
+                          overflow: &mut dyn Overflow<Input=T>) -> usize {
+  let mut overflowed : usize = 0;
+  let mut room = destination.room();
+  while room > 0 && overflow.backlog() > 0 {
+    let mut item = overflow.retry();
+    if let PourResult::Overflowed(_) = put_one(&mut item, destination, overflow) {
+      overflowed += 1;
+    }
+    room -= 1;
+  }
+  overflowed
+}
+
+fn put_one<T: Send>(value: &mut Option<T>,
+                    destination: &mut Sender<T>,
+                    overflow: &mut dyn Overflow<Input=T>)
+    -> PourResult {
+  //
+  let result = match destination.try_put(|old_value| mem::swap(value, old_value)) {
+    Some(seqno) => seqno,
+    None => {
+      // the setter did not run, value is still ours
+      overflow.overflow(value);
+      return PourResult::Rejected;
+    }
+  };
+
+  // the old_value should have been None, thus after the swap
+  // value is to be None
+
+  if value.is_some() {
+    panic!("value unexpectedly overwritten");
+  }
+
+  if take_displaced(destination, overflow) {
+    PourResult::Overflowed(result)
+  } else {
+    PourResult::Poured(result)
+  }
+}
+
+// check the content of the write buffer, and if there is anything
+// save it in the overflow buffer
+fn take_displaced<T: Send>(destination: &mut Sender<T>,
+                           overflow: &mut dyn Overflow<Input=T>) -> bool {
+  let mut none : Option<T> = None;
+  destination.tmp(|write_tmp| mem::swap(&mut none, write_tmp));
+  if none.is_some() {
+    overflow.overflow_at(destination.tmp_seqno(), &mut none);
+    true
+  } else {
+    false
+  }
+}
+
+// Keeps at most capacity overflowed items, the oldest ones go first
+// when it is full.
+pub struct Spill<T> {
+  items     : VecDeque<T>,
+  capacity  : usize,
+  dropped   : usize,
+}
+
+impl<T> Spill<T> {
+  pub fn new(capacity : usize) -> Spill<T> {
+    Spill {
+      items     : VecDeque::with_capacity(capacity),
+      capacity,
+      dropped   : 0,
+    }
+  }
+
+  #[inline(always)]
+  pub fn len(&self) -> usize {
+    self.items.len()
+  }
+
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.items.is_empty()
+  }
+
+  // number of overflowed items that did not fit
+  #[inline(always)]
+  pub fn dropped(&self) -> usize {
+    self.dropped
+  }
+
+  // takes the spilled items, oldest first
+  pub fn drain(&mut self) -> Drain<'_, T> {
+    self.items.drain(..)
+  }
+}
+
+impl<T: Send> Overflow for Spill<T> {
+  type Input = T;
+  fn overflow(&mut self, val : &mut Option<T>) {
+    if let Some(v) = val.take() {
+      if self.capacity == 0 {
+        self.dropped += 1;
+        return;
+      }
+      if self.items.len() == self.capacity {
+        self.items.pop_front();
+        self.dropped += 1;
+      }
+      self.items.push_back(v);
+    }
+  }
+}
+
+// drops the overflowed items, only counts them
+pub struct Discard<T> {
+  count     : usize,
+  _item     : PhantomData<fn(T)>,
+}
+
+impl<T> Discard<T> {
+  pub fn new() -> Discard<T> {
+    Discard { count: 0, _item: PhantomData }
+  }
+
+  #[inline(always)]
+  pub fn count(&self) -> usize {
+    self.count
+  }
+}
+
+impl<T> Default for Discard<T> {
+  fn default() -> Discard<T> {
+    Discard::new()
+  }
+}
+
+impl<T: Send> Overflow for Discard<T> {
+  type Input = T;
+  fn overflow(&mut self, val : &mut Option<T>) {
+    if val.take().is_some() {
+      self.count += 1;
+    }
+  }
+}
+
+// hands every overflowed item to a closure, which decides its fate
+pub struct Callback<T, F> {
+  callback  : F,
+  _item     : PhantomData<fn(T)>,
+}
+
+impl<T, F: FnMut(T)> Callback<T, F> {
+  pub fn new(callback : F) -> Callback<T, F> {
+    Callback { callback, _item: PhantomData }
+  }
+}
+
+impl<T: Send, F: FnMut(T)> Overflow for Callback<T, F> {
+  type Input = T;
+  fn overflow(&mut self, val : &mut Option<T>) {
+    if let Some(v) = val.take() {
+      (self.callback)(v);
+    }
+  }
+}
+
+// Keeps the overflowed items and puts them back into the channel on the
+// next pour(), once the reader has room for them. Until the backlog is
+// gone, new values queue up behind it instead of displacing more items.
+pub struct Retry<T> {
+  items     : VecDeque<T>,
+}
+
+impl<T> Retry<T> {
+  pub fn new() -> Retry<T> {
+    Retry { items: VecDeque::new() }
+  }
+
+  #[inline(always)]
+  pub fn len(&self) -> usize {
+    self.items.len()
+  }
+
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.items.is_empty()
+  }
+
+  // takes the items still waiting, oldest first
+  pub fn drain(&mut self) -> Drain<'_, T> {
+    self.items.drain(..)
+  }
+}
+
+impl<T> Default for Retry<T> {
+  fn default() -> Retry<T> {
+    Retry::new()
+  }
+}
+
+impl<T: Send> Overflow for Retry<T> {
+  type Input = T;
+  fn overflow(&mut self, val : &mut Option<T>) {
+    if let Some(v) = val.take() {
+      self.items.push_back(v);
+    }
+  }
+
+  #[inline(always)]
+  fn backlog(&self) -> usize {
+    self.items.len()
+  }
+
+  #[inline(always)]
+  fn retry(&mut self) -> Option<T> {
+    self.items.pop_front()
+  }
+}
+
+// A Sender that neither loses nor reorders items. What the reader has no
+// room for waits in a backlog, and every put() or flush() moves as much of
+// it into the channel as fits, ahead of the new items. The writer never
+// waits, only the backlog grows while the reader is behind.
+pub struct NoLossSender<T> {
+  sender    : Sender<T>,
+  backlog   : VecDeque<T>,
+}
+
+pub fn channel<T: Send>(size : usize) -> (NoLossSender<T>, Receiver<T>) {
+  let (tx, rx) = super::channel(size);
+  (NoLossSender::new(tx), rx)
+}
+
+impl<T: Send> NoLossSender<T> {
+  pub fn new(sender : Sender<T>) -> NoLossSender<T> {
+    NoLossSender { sender, backlog: VecDeque::new() }
+  }
+
+  // Poured with its seqno if value went into the channel, Deferred if
+  // it waits in the backlog for the reader to catch up
+  pub fn put(&mut self, value: T) -> PourResult {
+    if self.flush() == 0 && self.sender.room() > 0 {
+      // with room left no policy rejects it
+      match self.sender.send(value) {
+        Ok(seqno) => return PourResult::Poured(seqno),
+        Err(value) => self.backlog.push_back(value),
+      }
+    } else {
+      self.backlog.push_back(value);
+    }
+    PourResult::Deferred
+  }
+
+  // moves the backlog into the channel as far as the reader has room,
+  // returns the number of items still waiting
+  pub fn flush(&mut self) -> usize {
+    let n = self.sender.room().min(self.backlog.len());
+    if n > 0 {
+      self.sender.extend(self.backlog.drain(..n));
+    }
+    self.backlog.len()
+  }
+
+  #[inline(always)]
+  pub fn backlog(&self) -> usize {
+    self.backlog.len()
+  }
+
+  #[inline(always)]
+  pub fn sender(&self) -> &Sender<T> {
+    &self.sender
+  }
+
+  // the items still in the backlog are handed back, oldest first
+  pub fn into_inner(self) -> (Sender<T>, VecDeque<T>) {
+    (self.sender, self.backlog)
+  }
+}
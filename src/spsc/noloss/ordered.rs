@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::Overflow;
+use super::super::Receiver;
+use cb::IterRange;
+
+// An Overflow that keeps the displaced items by their seqno. It is a
+// handle, a clone of it goes to the Merger on the reader's side.
+pub struct OrderedSpill<T> {
+  items     : Arc<Mutex<BTreeMap<usize, T>>>,
+  rejected  : Arc<AtomicUsize>,
+}
+
+// Puts the items read from the channel and the ones in the spill back
+// into seqno order. Items after a missing seqno are held back until the
+// writer spills it, so the merged stream has no gaps.
+pub struct Merger<T> {
+  spill     : OrderedSpill<T>,
+  held      : BTreeMap<usize, T>,   // keyed by the distance from base
+  base      : usize,                // next as of the last merge
+  next      : usize,                // the seqno to hand out next
+}
+
+pub struct MergeIterator<'a, T: 'a> {
+  held      : &'a mut BTreeMap<usize, T>,
+  base      : usize,
+  next      : &'a mut usize,
+}
+
+impl<T> OrderedSpill<T> {
+  pub fn new() -> OrderedSpill<T> {
+    OrderedSpill {
+      items     : Arc::new(Mutex::new(BTreeMap::new())),
+      rejected  : Arc::new(AtomicUsize::new(0)),
+    }
+  }
+
+  // number of spilled items not merged yet
+  pub fn len(&self) -> usize {
+    self.items.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  // Number of items the channel's policy rejected. They never got a
+  // seqno, so they are not kept, but pour_all() counts them as
+  // overflowed all the same.
+  pub fn rejected(&self) -> usize {
+    self.rejected.load(Ordering::Relaxed)
+  }
+}
+
+impl<T> Clone for OrderedSpill<T> {
+  fn clone(&self) -> OrderedSpill<T> {
+    OrderedSpill { items: self.items.clone(), rejected: self.rejected.clone() }
+  }
+}
+
+impl<T> Default for OrderedSpill<T> {
+  fn default() -> OrderedSpill<T> {
+    OrderedSpill::new()
+  }
+}
+
+impl<T: Send> Overflow for OrderedSpill<T> {
+  type Input = T;
+
+  // without a seqno there is no place for the item in the merged stream
+  fn overflow(&mut self, val : &mut Option<T>) {
+    if val.take().is_some() {
+      self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  fn overflow_at(&mut self, seqno : usize, val : &mut Option<T>) {
+    if let Some(v) = val.take() {
+      self.items.lock().unwrap().insert(seqno, v);
+    }
+  }
+}
+
+impl<T: Send> Merger<T> {
+  pub fn new(spill : OrderedSpill<T>) -> Merger<T> {
+    Merger::with_seqno(spill, 0)
+  }
+
+  // starts the merged stream at seqno, tests use it to force a wraparound
+  pub(crate) fn with_seqno(spill : OrderedSpill<T>, seqno : usize) -> Merger<T> {
+    Merger { spill, held: BTreeMap::new(), base: seqno, next: seqno }
+  }
+
+  // Reads the channel and the spill, then returns the items that follow
+  // the last merged one without a gap.
+  pub fn merge(&mut self, rx: &mut Receiver<T>) -> MergeIterator<'_, T> {
+    // The seqnos wrap around, so the held items are ordered by their
+    // distance from next. Everything held is at or after next, so moving
+    // the base up to it keeps the order.
+    let moved = self.next.wrapping_sub(self.base);
+    if moved > 0 {
+      self.held = mem::take(&mut self.held).into_iter()
+        .map(|(k, v)| (k - moved, v))
+        .collect();
+      self.base = self.next;
+    }
+
+    let mut it = rx.iter();
+    while let Some(seqno) = it.next_id() {
+      if let Some(v) = it.next() {
+        self.held.insert(seqno.wrapping_sub(self.base), v);
+      }
+    }
+    let spilled = mem::take(&mut *self.spill.items.lock().unwrap());
+    for (seqno, v) in spilled {
+      self.held.insert(seqno.wrapping_sub(self.base), v);
+    }
+
+    // anything before a skipped gap comes too late, it lands in the upper half
+    self.held.split_off(&(usize::MAX / 2 + 1));
+
+    MergeIterator {
+      held    : &mut self.held,
+      base    : self.base,
+      next    : &mut self.next,
+    }
+  }
+
+  // the seqno the merged stream waits for
+  #[inline(always)]
+  pub fn next_seqno(&self) -> usize {
+    self.next
+  }
+
+  // number of items held back behind the missing seqno
+  #[inline(always)]
+  pub fn held(&self) -> usize {
+    self.held.len()
+  }
+
+  // Gives up on the missing seqnos before the oldest held item, in case
+  // the spill lost them. Returns the number of seqnos skipped.
+  pub fn skip_gap(&mut self) -> usize {
+    match self.held.keys().next() {
+      Some(&offset) => {
+        let skipped = offset - self.next.wrapping_sub(self.base);
+        self.next = self.base.wrapping_add(offset);
+        skipped
+      },
+      None => 0,
+    }
+  }
+}
+
+impl <'a, T: 'a> Iterator for MergeIterator<'a, T> {
+  type Item = T;
+
+  #[inline(always)]
+  fn next(&mut self) -> Option<T> {
+    let item = self.held.remove(&self.next.wrapping_sub(self.base))?;
+    *self.next = self.next.wrapping_add(1);
+    Some(item)
+  }
+}
@@ -0,0 +1,126 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::thread::{self, Thread};
+use std::time::Instant;
+#[cfg(feature = "async")]
+use std::task::Waker;
+
+// the waiter slot is owned by the reader in IDLE and REGISTERING and
+// by the writer in WAKING. in WAITING either side may claim it.
+const IDLE        : usize = 0;
+const REGISTERING : usize = 1;
+const WAITING     : usize = 2;
+const WAKING      : usize = 3;
+
+enum Waiter {
+  Thread(Thread),
+  #[cfg(feature = "async")]
+  Task(Waker),
+}
+
+pub struct Notify {
+  state   : AtomicUsize,
+  waiter  : UnsafeCell<Option<Waiter>>,
+}
+
+unsafe impl Sync for Notify { }
+
+impl Notify {
+  pub fn new() -> Notify {
+    Notify {
+      state   : AtomicUsize::new(IDLE),
+      waiter  : UnsafeCell::new(None),
+    }
+  }
+
+  // Called by the writer after publishing a new seqno with SeqCst. When
+  // nobody waits this is a single load, and it never waits for the reader.
+  #[inline(always)]
+  pub fn notify(&self) {
+    if self.state.load(Ordering::SeqCst) != WAITING {
+      return;
+    }
+    if self.state.compare_exchange(WAITING, WAKING, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+      let waiter = unsafe { (*self.waiter.get()).take() };
+      self.state.store(IDLE, Ordering::Release);
+      match waiter {
+        Some(Waiter::Thread(t)) => t.unpark(),
+        #[cfg(feature = "async")]
+        Some(Waiter::Task(w)) => w.wake(),
+        None => {},
+      }
+    }
+  }
+
+  // Parks the current thread until ready() returns true or the deadline
+  // passes. Returns the last result of ready().
+  pub fn wait<F>(&self, ready: F, deadline: Option<Instant>) -> bool
+    where F : Fn() -> bool
+  {
+    loop {
+      if ready() {
+        return true;
+      }
+
+      self.register(Waiter::Thread(thread::current()));
+
+      // the writer either sees WAITING or we see its seqno
+      fence(Ordering::SeqCst);
+      if ready() {
+        self.cancel();
+        return true;
+      }
+
+      match deadline {
+        Some(d) => {
+          let now = Instant::now();
+          if now >= d {
+            self.cancel();
+            return ready();
+          }
+          thread::park_timeout(d - now);
+        },
+        None => thread::park(),
+      }
+
+      self.cancel();
+    }
+  }
+
+  // Registers the task to be woken by the next notify and returns
+  // ready(). The registration stays in place until the writer wakes it
+  // or it is replaced by the next poll.
+  #[cfg(feature = "async")]
+  pub fn poll<F>(&self, ready: F, waker: &Waker) -> bool
+    where F : Fn() -> bool
+  {
+    if ready() {
+      return true;
+    }
+    self.register(Waiter::Task(waker.clone()));
+    fence(Ordering::SeqCst);
+    ready()
+  }
+
+  fn register(&self, waiter: Waiter) {
+    loop {
+      // a previous notify may still be on its way out, otherwise
+      // the slot is free or still holds our own earlier registration
+      let state = self.state.load(Ordering::Relaxed);
+      if state != WAKING &&
+         self.state.compare_exchange(state, REGISTERING, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+        break;
+      }
+      thread::yield_now();
+    }
+    unsafe { *self.waiter.get() = Some(waiter); }
+    self.state.store(WAITING, Ordering::SeqCst);
+  }
+
+  fn cancel(&self) {
+    if self.state.compare_exchange(WAITING, REGISTERING, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+      unsafe { *self.waiter.get() = None; }
+      self.state.store(IDLE, Ordering::Release);
+    }
+  }
+}
@@ -0,0 +1,102 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::vec;
+use futures_core::Stream;
+use futures_sink::Sink;
+use super::{Sender, Receiver};
+use super::super::cb::IterRange;
+
+// the items a single poll found in the channel
+pub struct Batch<T> {
+  items   : Vec<T>,
+  start   : usize,
+  skipped : usize,
+}
+
+impl<T> Batch<T> {
+  #[inline(always)]
+  pub fn items(&self) -> &[T] {
+    &self.items
+  }
+}
+
+impl<T> IntoIterator for Batch<T> {
+  type Item = T;
+  type IntoIter = vec::IntoIter<T>;
+
+  fn into_iter(self) -> vec::IntoIter<T> {
+    self.items.into_iter()
+  }
+}
+
+impl<T> IterRange for Batch<T> {
+
+  #[inline(always)]
+  fn get_range(&self) -> (usize, usize) {
+    (self.start, self.start.wrapping_add(self.items.len()))
+  }
+
+  #[inline(always)]
+  fn next_id(&self) -> Option<usize> {
+    if self.items.is_empty() {
+      None
+    } else {
+      Some(self.start)
+    }
+  }
+
+  #[inline(always)]
+  fn skipped(&self) -> usize {
+    self.skipped
+  }
+}
+
+impl<T: Send> Stream for Receiver<T> {
+  type Item = Batch<T>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Batch<T>>> {
+    let this = self.get_mut();
+    let mut skipped = 0;
+    loop {
+      if !this.inner.notify.poll(|| this.is_ready(), cx.waker()) {
+        return Poll::Pending;
+      }
+      if this.is_drained() {
+        return Poll::Ready(None);
+      }
+      // the head may have moved while the writer lapped the fetch, then
+      // there is nothing to hand out and the reader has to wait again
+      let it = this.iter();
+      let (start, _) = it.get_range();
+      skipped += it.skipped();
+      let items : Vec<T> = it.collect();
+      if !items.is_empty() {
+        return Poll::Ready(Some(Batch { items, start, skipped, }));
+      }
+    }
+  }
+}
+
+// putting never waits for the reader, so the sink is always ready
+impl<T: Send> Sink<T> for Sender<T> {
+  type Error = Infallible;
+
+  fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Infallible> {
+    let mut item = Some(item);
+    self.get_mut().put(|v| *v = item.take());
+    Ok(())
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+    Poll::Ready(Ok(()))
+  }
+}
@@ -1,11 +1,16 @@
 use spsc;
-use std::sync::{Arc, atomic};
+use std::sync::{Arc, Mutex, atomic};
 use std::mem;
 use std::thread;
-use cb::IterRange;
+use cb::{CircularBuffer, IterRange};
 use super::noloss::*;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::{env, fs, io, process};
+use std::io::Write;
+use std::path::PathBuf;
+use super::noloss::{disk, ordered};
 use time;
 
 #[test]
@@ -106,15 +111,16 @@ impl Overflow for Destination {
   }
 }
 
-fn pour_in(sz: usize) {
-  // how to prevent overwrite
-  // passing 100_000 values with overflowing if
-  // reader falls behind
+// pours 10_000 values from another thread while reading them here,
+// returns what was read and the overflow that got the rest
+fn pour_through<O>(sz: usize, overflow: O) -> (HashSet<i32>, O)
+  where O : Overflow<Input=i32> + Send + 'static
+{
   let flag = Arc::new(atomic::AtomicBool::new(false));
   let flag2 = flag.clone();
   let (mut tx, mut rx) = spsc::channel::<i32>(sz);
   let t = thread::spawn(move|| {
-    let mut dest = Destination::new();
+    let mut dest = overflow;
     for i in 0..10_000i32 {
       let mut x = Some(i);
       pour(&mut x, &mut tx, &mut dest);
@@ -129,10 +135,10 @@ fn pour_in(sz: usize) {
   loop {
     if recvd.len() == 10_000 { break; }
     for i in rx.iter() {
-      assert_eq!(recvd.contains(&i), false);
+      assert!(!recvd.contains(&i));
       recvd.insert(i);
     }
-    if flag2.load(atomic::Ordering::Acquire) == true {
+    if flag2.load(atomic::Ordering::Acquire) {
       retry -= 1;
       if retry == 0 {
         break;
@@ -142,26 +148,450 @@ fn pour_in(sz: usize) {
       break;
     }
   }
-  let dest = t.join().unwrap();
-  for i in &dest.overflow {
-    assert_eq!(recvd.contains(&i), false);
+  (recvd, t.join().unwrap())
+}
+
+// Writes 10_000 values from another thread into channels of 20 sizes
+// while read() collects them here, they have to come in order.
+fn in_order_through<Tx, Rx>(channel: fn(usize) -> (Tx, Rx), write: fn(Tx), read: fn(&mut Rx, &mut Vec<i32>))
+  where Tx : Send + 'static
+{
+  for i in 0..20usize {
+    let (tx, mut rx) = channel(1+(i*13));
+    let t = thread::spawn(move|| write(tx));
+
+    let started_at = time::precise_time_s();
+    let mut recvd = Vec::with_capacity(10_000);
+    while recvd.len() < 10_000 && started_at+10.0 > time::precise_time_s() {
+      let n = recvd.len();
+      read(&mut rx, &mut recvd);
+      if recvd.len() == n { thread::yield_now(); }
+    }
+    // a writer waiting for the reader gives up once it is gone
+    drop(rx);
+    t.join().unwrap();
+    assert_eq!(recvd, (0..10_000).collect::<Vec<i32>>());
+  }
+}
+
+type SpillingSender = (spsc::Sender<i32>, ordered::OrderedSpill<i32>);
+type MergingReceiver = (spsc::Receiver<i32>, ordered::Merger<i32>);
+
+// the writer's side pours into the spill, the reader's side merges it back
+fn merged_channel(sz: usize) -> (SpillingSender, MergingReceiver) {
+  let (tx, rx) = spsc::channel::<i32>(sz);
+  let spill = ordered::OrderedSpill::new();
+  let merger = ordered::Merger::new(spill.clone());
+  ((tx, spill), (rx, merger))
+}
+
+// the overflowed items and the ones read make up all of them
+fn check_all(mut recvd: HashSet<i32>, overflowed: &[i32]) {
+  for i in overflowed {
+    assert!(!recvd.contains(i));
     recvd.insert(*i);
   }
   for i in 0..10_000i32 {
     assert_eq!(recvd.get(&i), Some(&i));
-    assert_eq!(true, recvd.contains(&i));
+    assert!(recvd.contains(&i));
   }
-  assert!(recvd.len() > 0);
+  assert!(!recvd.is_empty());
   assert_eq!(recvd.len(), 10_000);
 }
 
 #[test]
 fn pour_test() {
   for i in 0..40 as usize {
-    pour_in(1+(i*13));
+    let (recvd, dest) = pour_through(1+(i*13), Destination::new());
+    check_all(recvd, &dest.overflow.into_iter().collect::<Vec<i32>>());
+  }
+}
+
+#[test]
+fn pour_spill() {
+  for i in 0..40usize {
+    let (recvd, mut spill) = pour_through(1+(i*13), Spill::new(10_000));
+    assert_eq!(spill.dropped(), 0);
+    check_all(recvd, &spill.drain().collect::<Vec<i32>>());
+  }
+}
+
+#[test]
+fn pour_discard() {
+  for i in 0..40usize {
+    let (recvd, discard) = pour_through(1+(i*13), Discard::new());
+    assert_eq!(recvd.len() + discard.count(), 10_000);
+  }
+}
+
+#[test]
+fn pour_callback() {
+  for i in 0..40usize {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen2 = seen.clone();
+    let callback = Callback::new(move |v| seen2.lock().unwrap().push(v));
+    let (recvd, _callback) = pour_through(1+(i*13), callback);
+    check_all(recvd, &seen.lock().unwrap());
+  }
+}
+
+#[test]
+fn pour_retry() {
+  for i in 0..40usize {
+    let (recvd, mut retry) = pour_through(1+(i*13), Retry::new());
+    check_all(recvd, &retry.drain().collect::<Vec<i32>>());
+  }
+}
+
+#[test]
+fn spill_bounded() {
+  let (mut tx, mut rx) = spsc::channel::<i32>(2);
+  let mut spill = Spill::new(1);
+  for i in 0..5 {
+    pour(&mut Some(i), &mut tx, &mut spill);
+  }
+  // 0, 1 and 2 were displaced, only the last of them is kept
+  assert_eq!(spill.dropped(), 2);
+  assert_eq!(spill.drain().collect::<Vec<i32>>(), vec![2]);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![3, 4]);
+}
+
+#[test]
+fn retry_on_next_pour() {
+  let (mut tx, mut rx) = spsc::channel::<i32>(2);
+  let mut retry = Retry::new();
+  for i in 0..4 {
+    pour(&mut Some(i), &mut tx, &mut retry);
+  }
+  // 2 displaced 0, then 3 had to wait behind it
+  assert_eq!(retry.len(), 2);
+
+  // no room yet, the new value queues up behind the backlog
+  pour(&mut Some(4), &mut tx, &mut retry);
+  assert_eq!(retry.len(), 3);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1, 2]);
+
+  // the reader caught up, the oldest ones go first
+  pour(&mut Some(5), &mut tx, &mut retry);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 3]);
+  assert_eq!(retry.drain().collect::<Vec<i32>>(), vec![4, 5]);
+  assert_eq!(tx.room(), 2);
+}
+
+#[test]
+fn noloss_sender_order() {
+  let (mut tx, mut rx) = channel::<i32>(2);
+  for i in 0..5 {
+    tx.put(i);
+  }
+  assert_eq!(tx.backlog(), 3);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 1]);
+
+  // the backlog goes ahead of the new item
+  assert_eq!(tx.put(5), PourResult::Deferred);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![2, 3]);
+  assert_eq!(tx.flush(), 0);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![4, 5]);
+  assert_eq!(tx.put(6), PourResult::Poured(6));
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![6]);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn noloss_sender_threaded() {
+  in_order_through(channel::<i32>,
+    |mut tx| {
+      for i in 0..10_000i32 {
+        tx.put(i);
+      }
+      while tx.flush() > 0 && tx.sender().receiver_alive() {
+        thread::yield_now();
+      }
+    },
+    |rx, recvd| {
+      recvd.extend(rx.iter());
+      assert_eq!(rx.dropped(), 0);
+    });
+}
+
+struct I32Codec;
+
+impl disk::Codec<i32> for I32Codec {
+  fn encode(&mut self, value: &i32, out: &mut Vec<u8>) -> io::Result<()> {
+    out.extend_from_slice(&value.to_le_bytes());
+    Ok(())
+  }
+
+  fn decode(&mut self, bytes: &[u8]) -> io::Result<i32> {
+    match <[u8; 4]>::try_from(bytes) {
+      Ok(b) => Ok(i32::from_le_bytes(b)),
+      Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "not an i32")),
+    }
   }
 }
 
+fn spill_dir(name: &str) -> PathBuf {
+  let dir = env::temp_dir().join(format!("lossyq-{}-{}", process::id(), name));
+  let _ = fs::remove_dir_all(&dir);
+  dir
+}
+
+#[test]
+fn pour_disk_spill() {
+  let dir = spill_dir("pour");
+  for i in 0..10usize {
+    let spill = disk::DiskSpill::create(&dir, I32Codec, 1024, usize::MAX).unwrap();
+    let (recvd, mut spill) = pour_through(1+(i*13), spill);
+    spill.seal().unwrap();
+    assert_eq!(spill.errors(), 0);
+    assert_eq!(spill.dropped(), 0);
+    let replayed = disk::Replay::open(&dir, I32Codec).collect::<io::Result<Vec<i32>>>().unwrap();
+    assert_eq!(replayed.len(), spill.spilled());
+    check_all(recvd, &replayed);
+  }
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn disk_spill_rotation() {
+  let dir = spill_dir("rotation");
+  // 8 bytes per record, 4 records per segment, 3 segments at most
+  let mut spill = disk::DiskSpill::create(&dir, I32Codec, 32, 96).unwrap();
+  let mut replay = disk::Replay::open(&dir, I32Codec);
+  for i in 0..10 {
+    spill.overflow(&mut Some(i));
+  }
+  // only the sealed segments are visible
+  assert_eq!(replay.by_ref().map(|r| r.unwrap()).collect::<Vec<i32>>(), (0..8).collect::<Vec<i32>>());
+
+  // the replayed segments go away without a loss, then the oldest
+  // unreplayed ones make room for the new items
+  for i in 10..30 {
+    spill.overflow(&mut Some(i));
+  }
+  spill.seal().unwrap();
+  assert_eq!(spill.spilled(), 30);
+  assert_eq!(spill.dropped(), 12);
+  assert_eq!(replay.map(|r| r.unwrap()).collect::<Vec<i32>>(), (20..30).collect::<Vec<i32>>());
+  assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn disk_spill_torn_record() {
+  let dir = spill_dir("torn");
+  {
+    let mut spill = disk::DiskSpill::create(&dir, I32Codec, 1024, 4096).unwrap();
+    spill.overflow(&mut Some(1));
+    spill.overflow(&mut Some(2));
+  }
+  // a crash in the middle of the third record
+  let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+  fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&[4, 0, 0, 0, 3]).unwrap();
+
+  let mut replay = disk::Replay::open(&dir, I32Codec);
+  assert_eq!(replay.next().unwrap().unwrap(), 1);
+  assert_eq!(replay.next().unwrap().unwrap(), 2);
+  assert_eq!(replay.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+  assert!(replay.next().is_none());
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn disk_spill_seal_error() {
+  let dir = spill_dir("seal-error");
+  let mut spill = disk::DiskSpill::create(&dir, I32Codec, 1024, 4096).unwrap();
+  spill.overflow(&mut Some(1));
+  // the open segment cannot be renamed without its directory
+  fs::remove_dir_all(&dir).unwrap();
+  assert!(spill.seal().is_err());
+
+  // the failed segment's index is not used again, and neither is one
+  // that turned up meanwhile
+  fs::create_dir_all(&dir).unwrap();
+  fs::write(dir.join(format!("{:020}.seg", 1)), [4, 0, 0, 0, 7, 0, 0, 0]).unwrap();
+  spill.overflow(&mut Some(2));
+  spill.seal().unwrap();
+  assert_eq!(spill.errors(), 0);
+  assert!(dir.join(format!("{:020}.seg", 2)).exists());
+  let replayed = disk::Replay::open(&dir, I32Codec).collect::<io::Result<Vec<i32>>>().unwrap();
+  assert_eq!(replayed, vec![7, 2]);
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn merge_in_order() {
+  let (mut tx, mut rx) = spsc::channel::<i32>(2);
+  let mut spill = ordered::OrderedSpill::new();
+  let mut merger = ordered::Merger::new(spill.clone());
+  for i in 0..5 {
+    pour(&mut Some(i), &mut tx, &mut spill);
+  }
+  assert_eq!(spill.len(), 3);
+  assert_eq!(merger.merge(&mut rx).collect::<Vec<i32>>(), vec![0, 1, 2, 3, 4]);
+  assert!(spill.is_empty());
+
+  // a seqno the spill never got holds the rest back
+  tx.put(|v| *v = Some(5));
+  tx.put(|v| *v = Some(6));
+  tx.put(|v| *v = Some(7));
+  assert_eq!(merger.merge(&mut rx).count(), 0);
+  assert_eq!(merger.held(), 2);
+  assert_eq!(merger.skip_gap(), 1);
+  assert_eq!(merger.merge(&mut rx).collect::<Vec<i32>>(), vec![6, 7]);
+  assert_eq!(merger.next_seqno(), 8);
+}
+
+#[test]
+fn merge_wraparound() {
+  let (mut tx, mut rx) = spsc::from_buffer(CircularBuffer::with_seqno(2, usize::MAX-2));
+  let mut spill = ordered::OrderedSpill::new();
+  let mut merger = ordered::Merger::with_seqno(spill.clone(), usize::MAX-2);
+  for i in 0..6 {
+    pour(&mut Some(i), &mut tx, &mut spill);
+  }
+  // the spilled ones are before the turnover, the rest after it
+  assert_eq!(merger.merge(&mut rx).collect::<Vec<i32>>(), vec![0, 1, 2, 3, 4, 5]);
+  assert_eq!(merger.next_seqno(), 3);
+
+  tx.put(|v| *v = Some(6));
+  tx.put(|v| *v = Some(7));
+  assert_eq!(merger.skip_gap(), 0);
+  assert_eq!(merger.merge(&mut rx).collect::<Vec<i32>>(), vec![6, 7]);
+}
+
+#[test]
+fn merge_gap_at_turnover() {
+  let (mut tx, mut rx) = spsc::from_buffer(CircularBuffer::with_seqno(4, usize::MAX-1));
+  let mut merger = ordered::Merger::with_seqno(ordered::OrderedSpill::new(), usize::MAX-2);
+  for i in 0..4 {
+    tx.put(|v| *v = Some(i));
+  }
+  // usize::MAX-2 never shows up, the ones after the turnover wait too
+  assert_eq!(merger.merge(&mut rx).count(), 0);
+  assert_eq!(merger.held(), 4);
+  assert_eq!(merger.skip_gap(), 1);
+  assert_eq!(merger.merge(&mut rx).collect::<Vec<i32>>(), vec![0, 1, 2, 3]);
+  assert_eq!(merger.next_seqno(), 2);
+}
+
+#[test]
+fn ordered_spill_rejected() {
+  let (mut tx, mut rx) = spsc::channel_with::<i32>(2, spsc::Policy::RejectNewest);
+  let mut spill = ordered::OrderedSpill::new();
+  let summary = pour_all(0..5, &mut tx, &mut spill);
+  assert_eq!(summary.overflowed, 3);
+  // without a seqno they cannot be merged, but they are counted
+  assert!(spill.is_empty());
+  assert_eq!(spill.rejected(), 3);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 1]);
+}
+
+#[test]
+fn merge_threaded() {
+  in_order_through(merged_channel,
+    |(mut tx, mut spill)| {
+      for i in 0..10_000i32 {
+        pour(&mut Some(i), &mut tx, &mut spill);
+      }
+    },
+    |(rx, merger), merged| merged.extend(merger.merge(rx)));
+}
+
+#[test]
+fn pour_result_seqno() {
+  let (mut tx, mut rx) = spsc::channel::<i32>(2);
+  let mut retry = Retry::new();
+  assert_eq!(pour(&mut Some(0), &mut tx, &mut retry), PourResult::Poured(0));
+  assert_eq!(pour(&mut Some(1), &mut tx, &mut retry), PourResult::Poured(1));
+  assert_eq!(pour(&mut Some(2), &mut tx, &mut retry), PourResult::Overflowed(2));
+  assert_eq!(pour(&mut Some(3), &mut tx, &mut retry), PourResult::Deferred);
+  assert_eq!(PourResult::Deferred.seqno(), None);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1, 2]);
+  // the backlog takes the room the reader made, 4 waits behind it
+  assert_eq!(pour(&mut Some(4), &mut tx, &mut retry), PourResult::Deferred);
+  assert_eq!(pour(&mut Some(5), &mut tx, &mut retry), PourResult::Deferred);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 3]);
+  assert_eq!(pour(&mut Some(6), &mut tx, &mut retry), PourResult::Deferred);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![4, 5]);
+  // the backlog is gone with room to spare
+  assert_eq!(pour(&mut Some(7), &mut tx, &mut retry), PourResult::Poured(8));
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![6, 7]);
+  assert!(retry.is_empty());
+}
+
+#[test]
+fn pour_all_summary() {
+  let (mut tx, mut rx) = spsc::channel::<i32>(4);
+  let mut spill = Spill::new(100);
+  let summary = pour_all(0..10, &mut tx, &mut spill);
+  assert_eq!(summary, PourSummary { poured: 10, overflowed: 6, first_seqno: Some(0), last_seqno: Some(9) });
+  assert_eq!(spill.drain().collect::<Vec<i32>>(), (0..6).collect::<Vec<i32>>());
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), (6..10).collect::<Vec<i32>>());
+
+  let summary = pour_all(10..12, &mut tx, &mut spill);
+  assert_eq!(summary, PourSummary { poured: 2, overflowed: 0, first_seqno: Some(10), last_seqno: Some(11) });
+  assert_eq!(pour_all(0..0, &mut tx, &mut spill), PourSummary::default());
+}
+
+#[test]
+fn pour_all_deferred() {
+  // the same as pour_result_seqno, with a batch for each step
+  let (mut tx, mut rx) = spsc::channel::<i32>(2);
+  let mut retry = Retry::new();
+  // 2 displaces 0, then 3 waits behind it
+  let summary = pour_all(0..4, &mut tx, &mut retry);
+  assert_eq!(summary, PourSummary { poured: 3, overflowed: 2, first_seqno: Some(0), last_seqno: Some(2) });
+  // no room, everything queues up behind the backlog
+  let summary = pour_all(4..6, &mut tx, &mut retry);
+  assert_eq!(summary, PourSummary { poured: 0, overflowed: 2, first_seqno: None, last_seqno: None });
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1, 2]);
+  let summary = pour_all(6..7, &mut tx, &mut retry);
+  assert_eq!(summary.poured, 0);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 3]);
+  assert_eq!(retry.drain().collect::<Vec<i32>>(), vec![4, 5, 6]);
+}
+
+#[test]
+fn pour_all_like_pour() {
+  let (mut tx, mut rx) = spsc::channel::<i32>(3);
+  let mut retry = Retry::new();
+  for i in 0..5 {
+    pour(&mut Some(i), &mut tx, &mut retry);
+  }
+  let (mut all_tx, mut all_rx) = spsc::channel::<i32>(3);
+  let mut all_retry = Retry::new();
+  pour_all(0..5, &mut all_tx, &mut all_retry);
+  assert_eq!(all_rx.iter().collect::<Vec<i32>>(), rx.iter().collect::<Vec<i32>>());
+  assert_eq!(all_retry.drain().collect::<Vec<i32>>(), retry.drain().collect::<Vec<i32>>());
+}
+
+#[test]
+fn pour_all_behind_backlog() {
+  let (mut tx, mut rx) = spsc::channel::<i32>(4);
+  let mut retry = Retry::new();
+  assert_eq!(pour_all(0..6, &mut tx, &mut retry).overflowed, 2);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1, 2, 3, 4]);
+  // 0 and 5 take half the room, only two new items fit behind them
+  let summary = pour_all(6..10, &mut tx, &mut retry);
+  assert_eq!(summary, PourSummary { poured: 2, overflowed: 2, first_seqno: Some(7), last_seqno: Some(8) });
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 5, 6, 7]);
+  assert_eq!(retry.drain().collect::<Vec<i32>>(), vec![8, 9]);
+}
+
+#[test]
+fn pour_all_merged() {
+  in_order_through(merged_channel,
+    |(mut tx, mut spill)| {
+      for chunk in 0..100i32 {
+        let summary = pour_all(chunk*100..(chunk+1)*100, &mut tx, &mut spill);
+        assert_eq!(summary.first_seqno, Some((chunk*100) as usize));
+        assert_eq!(summary.last_seqno, Some((chunk*100+99) as usize));
+      }
+    },
+    |(rx, merger), merged| merged.extend(merger.merge(rx)));
+}
+
 #[test]
 fn with_spawn() {
   // put a few objects in one thread and get them in the main
@@ -218,3 +648,427 @@ fn range() {
   assert_eq!(to, 3);
   assert_eq!(Some(1),i.next_id());
 }
+
+#[test]
+fn dropped_count() {
+  // the receiver keeps a running total of the items it
+  // never got to see
+  let (mut tx, mut rx) = spsc::channel(4);
+  for i in 0..10 {
+    tx.put(|v| *v = Some(i));
+  }
+  let i = rx.iter();
+  assert_eq!(i.skipped(), 6);
+  assert_eq!(i.sum::<i32>(), 6+7+8+9);
+  assert_eq!(rx.dropped(), 6);
+  for i in 0..5 {
+    tx.put(|v| *v = Some(i));
+  }
+  assert_eq!(rx.iter().count(), 4);
+  assert_eq!(rx.dropped(), 7);
+}
+
+#[test]
+fn wait_for_data() {
+  use std::time::Duration;
+  let (mut tx, mut rx) = spsc::channel(4);
+  let t = thread::spawn(move|| {
+    for i in 0..100 {
+      thread::sleep(Duration::from_millis(1));
+      tx.put(|v| *v = Some(i));
+    }
+  });
+  let mut recvd = 0;
+  while recvd < 100 {
+    let i = rx.wait_iter();
+    let skipped = i.skipped();
+    let c = i.count();
+    assert!(c > 0);
+    recvd += c + skipped;
+  }
+  t.join().unwrap();
+}
+
+#[test]
+fn wait_timeout() {
+  use std::time::{Duration, Instant};
+  let (mut tx, mut rx) = spsc::channel(4);
+  let started_at = Instant::now();
+  assert_eq!(rx.wait_iter_timeout(Duration::from_millis(20)).count(), 0);
+  assert!(started_at.elapsed() >= Duration::from_millis(20));
+  // returns right away if there is something to read
+  tx.put(|v| *v = Some(1));
+  let started_at = Instant::now();
+  assert_eq!(rx.wait_iter_timeout(Duration::from_secs(10)).next(), Some(1));
+  assert!(started_at.elapsed() < Duration::from_secs(10));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn stream_batches() {
+  use futures::executor::block_on;
+  use futures::{SinkExt, StreamExt};
+  let (mut tx, mut rx) = spsc::channel(4);
+  // Sender::send() puts right away, the sink's send goes through the future
+  block_on(SinkExt::send(&mut tx, 1)).unwrap();
+  block_on(SinkExt::send(&mut tx, 2)).unwrap();
+  let b = block_on(rx.next()).unwrap();
+  assert_eq!(b.get_range(), (0, 2));
+  assert_eq!(b.items(), &[1, 2]);
+  for i in 3..10 {
+    block_on(tx.feed(i)).unwrap();
+  }
+  let b = block_on(rx.next()).unwrap();
+  assert_eq!(b.skipped(), 3);
+  assert_eq!(b.into_iter().collect::<Vec<i32>>(), vec![6, 7, 8, 9]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn stream_wakeup() {
+  use futures::executor::block_on_stream;
+  use std::time::Duration;
+  let (mut tx, rx) = spsc::channel(4);
+  let t = thread::spawn(move|| {
+    for i in 0..100 {
+      thread::sleep(Duration::from_millis(1));
+      tx.put(|v| *v = Some(i));
+    }
+  });
+  let mut recvd = 0;
+  for b in block_on_stream(rx) {
+    assert!(!b.items().is_empty());
+    recvd += b.items().len() + b.skipped();
+    if recvd == 100 { break; }
+  }
+  assert_eq!(recvd, 100);
+  t.join().unwrap();
+}
+
+#[test]
+fn sender_disconnect() {
+  let (mut tx, mut rx) = spsc::channel(4);
+  tx.put(|v| *v = Some(1));
+  assert!(!rx.is_disconnected());
+  assert_eq!(rx.try_iter().unwrap().count(), 1);
+  // no new data is not the same as a disconnect
+  assert_eq!(rx.try_iter().unwrap().count(), 0);
+  tx.put(|v| *v = Some(2));
+  drop(tx);
+  assert!(rx.is_disconnected());
+  assert_eq!(rx.try_iter().unwrap().next(), Some(2));
+  assert_eq!(rx.try_iter().err(), Some(spsc::Disconnected));
+}
+
+#[test]
+fn receiver_disconnect() {
+  let (mut tx, rx) = spsc::channel(4);
+  assert!(tx.receiver_alive());
+  drop(rx);
+  assert!(!tx.receiver_alive());
+  // putting still works, nobody reads it though
+  tx.put(|v| *v = Some(1));
+}
+
+#[test]
+fn wait_disconnect() {
+  use std::time::Duration;
+  let (tx, mut rx) = spsc::channel::<i32>(4);
+  let t = thread::spawn(move|| {
+    thread::sleep(Duration::from_millis(10));
+    drop(tx);
+  });
+  assert_eq!(rx.wait_iter().count(), 0);
+  assert!(rx.is_disconnected());
+  t.join().unwrap();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn stream_end() {
+  use futures::executor::block_on_stream;
+  let (mut tx, rx) = spsc::channel(4);
+  let t = thread::spawn(move|| {
+    for i in 0..3 {
+      tx.put(|v| *v = Some(i));
+    }
+  });
+  let items : Vec<i32> = block_on_stream(rx).flat_map(|b| b.into_iter()).collect();
+  assert_eq!(items, vec![0, 1, 2]);
+  t.join().unwrap();
+}
+
+#[test]
+fn peek_then_read() {
+  let (mut tx, mut rx) = spsc::channel(4);
+  tx.put(|v| *v = Some(1));
+  tx.put(|v| *v = Some(2));
+  assert_eq!(rx.peek_latest(), Some(&2));
+  assert_eq!(rx.peek_iter().count(), 2);
+  assert_eq!(rx.iter().sum::<i32>(), 3);
+  assert_eq!(rx.peek_latest(), None);
+}
+
+#[test]
+fn peeker_leaves_items() {
+  let (mut tx, mut rx) = spsc::channel(3);
+  let peeker = rx.peeker();
+  assert_eq!(peeker.peek_latest(), None);
+  tx.extend(1..4);
+  assert_eq!(peeker.peek_iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+  assert_eq!(peeker.clone().peek_latest(), Some(3));
+  // the receiver still gets everything, 2 and 3 stay in their elements
+  assert_eq!(rx.iter().next(), Some(1));
+  assert_eq!(peeker.peek_iter().count(), 0);
+  tx.put(|v| *v = Some(4));
+  // the receiver fetched 4 and put back the elements holding 2 and 3
+  assert_eq!(rx.iter_partial().get_range(), (3, 4));
+  assert_eq!(peeker.peek_iter().count(), 0);
+  tx.put(|v| *v = Some(5));
+  assert_eq!(peeker.peek_iter().collect::<Vec<i32>>(), vec![5]);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![4, 5]);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn peeker_thread() {
+  // a monitor peeks all along, the receiver still gets every item in order
+  let (mut tx, mut rx) = spsc::channel::<Box<usize>>(8);
+  let peeker = rx.peeker();
+  let done = Arc::new(atomic::AtomicBool::new(false));
+  let done2 = done.clone();
+  let monitor = thread::spawn(move|| {
+    let mut peeks = 0;
+    while !done2.load(atomic::Ordering::Acquire) {
+      let items = peeker.peek_iter().map(|v| *v).collect::<Vec<usize>>();
+      assert!(items.windows(2).all(|w| w[0] < w[1]));
+      assert!(items.len() <= 8);
+      peeks += 1;
+      thread::yield_now();
+    }
+    peeks
+  });
+  let writer = thread::spawn(move|| {
+    for i in 0..10_000 {
+      let mut x = Some(Box::new(i));
+      while x.is_some() {
+        // don't overwrite, wait for the receiver
+        if tx.room() > 0 {
+          tx.put(|v| *v = x.take());
+        } else {
+          thread::yield_now();
+        }
+      }
+    }
+  });
+  let mut next = 0;
+  while next < 10_000 {
+    for v in rx.iter() {
+      assert_eq!(*v, next);
+      next += 1;
+    }
+    thread::yield_now();
+  }
+  writer.join().unwrap();
+  done.store(true, atomic::Ordering::Release);
+  assert!(monitor.join().unwrap() > 0);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn at_least_once() {
+  // with iter_partial() only the items returned by next() are gone
+  let (mut tx, mut rx) = spsc::channel(20);
+  tx.put(|v| *v = Some(1));
+  tx.put(|v| *v = Some(2));
+  tx.put(|v| *v = Some(3));
+  {
+    let mut it = rx.iter_partial();
+    assert_eq!(Some(1), it.next());
+  }
+  {
+    let mut it = rx.iter_partial();
+    assert_eq!(Some(2), it.next());
+    assert_eq!(Some(3), it.next());
+    assert_eq!(None, it.next());
+  }
+  assert_eq!(rx.iter().count(), 0);
+}
+
+#[test]
+fn batch_with_reader() {
+  // the reader only ever sees whole batches
+  let (mut tx, mut rx) = spsc::channel::<usize>(64);
+  let t = thread::spawn(move|| {
+    for b in 0..1_000usize {
+      tx.put_batch(4, |i, v| *v = Some(b*4+i));
+    }
+    tx.extend(vec![4_000, 4_001]);
+  });
+  let mut last = None;
+  loop {
+    let done = rx.is_disconnected();
+    let i = rx.iter();
+    let (from, to) = i.get_range();
+    assert!(to % 4 == 0 || to == 4_002);
+    for v in i {
+      if let Some(prev) = last { assert!(prev < v); }
+      last = Some(v);
+      assert!(v >= from);
+    }
+    if done { break; }
+  }
+  assert_eq!(last, Some(4_001));
+  t.join().unwrap();
+}
+
+#[test]
+fn handles_are_send() {
+  fn is_send<S: Send>(_s: &S) -> bool { true }
+  let (tx, rx) = spsc::channel::<Box<String>>(2);
+  assert!(is_send(&tx));
+  assert!(is_send(&rx));
+  thread::spawn(move|| drop(tx)).join().unwrap();
+  thread::spawn(move|| drop(rx)).join().unwrap();
+}
+
+#[test]
+fn reject_newest() {
+  use cb::Policy;
+  let (mut tx, mut rx) = spsc::channel_with::<i32>(2, Policy::RejectNewest);
+  assert_eq!(tx.send(1), Ok(0));
+  assert_eq!(tx.send(2), Ok(1));
+  assert_eq!(tx.send(3), Err(3));
+  // a rejected put doesn't call the setter and doesn't take a seqno
+  assert_eq!(tx.try_put(|_v| panic!("rejected")), None);
+  assert_eq!(tx.seqno(), 2);
+  assert_eq!(tx.rejected(), 2);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1, 2]);
+  // the reader caught up, there is room again
+  assert_eq!(tx.send(4), Ok(2));
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![4]);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn reject_after_peek() {
+  use cb::Policy;
+  let (mut tx, mut rx) = spsc::channel_with::<i32>(2, Policy::RejectNewest);
+  assert_eq!(tx.send(0), Ok(0));
+  assert_eq!(tx.send(1), Ok(1));
+  // peeked items are still unread, they don't make room
+  assert_eq!(rx.peek_iter().count(), 2);
+  assert_eq!(tx.send(2), Err(2));
+  assert_eq!(tx.send(3), Err(3));
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 1]);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn reject_after_partial() {
+  use cb::Policy;
+  let (mut tx, mut rx) = spsc::channel_with::<i32>(3, Policy::RejectNewest);
+  tx.extend(0..3);
+  assert_eq!(rx.iter_partial().next(), Some(0));
+  // only the item handed out made room
+  assert_eq!(tx.send(3), Ok(3));
+  assert_eq!(tx.send(4), Err(4));
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn policy_hook() {
+  use cb::Policy;
+  // overwrite once, then reject. the overwritten item still counts as unread.
+  let mut calls = Vec::new();
+  let hook = Box::new(move |unread| {
+    calls.push(unread);
+    assert_eq!(calls, (3..unread+1).collect::<Vec<usize>>());
+    calls.len() == 1
+  });
+  let (mut tx, mut rx) = spsc::channel_with::<i32>(3, Policy::Hook(hook));
+  tx.extend(0..5);
+  assert_eq!(tx.rejected(), 1);
+  let i = rx.iter();
+  assert_eq!(i.skipped(), 1);
+  assert_eq!(i.collect::<Vec<i32>>(), vec![1, 2, 3]);
+}
+
+#[test]
+fn batch_rejected() {
+  use cb::Policy;
+  let (mut tx, mut rx) = spsc::channel_with::<usize>(3, Policy::RejectNewest);
+  tx.put(|v| *v = Some(0));
+  // the batch stops at the first rejected item
+  assert_eq!(tx.put_batch(4, |i, v| *v = Some(10+i)), (1, 2));
+  assert_eq!(tx.put_batch(1, |_i, _v| panic!("rejected")), (3, 0));
+  assert_eq!(tx.seqno(), 3);
+  assert_eq!(rx.iter().collect::<Vec<usize>>(), vec![0, 10, 11]);
+}
+
+#[test]
+fn pour_reject_newest() {
+  use cb::Policy;
+  let (mut tx, mut rx) = spsc::channel_with::<i32>(2, Policy::RejectNewest);
+  let mut spill = Spill::new(10);
+  for i in 0..2 {
+    assert_eq!(pour(&mut Some(i), &mut tx, &mut spill), PourResult::Poured(i as usize));
+  }
+  // the rejected values go to the overflow, nothing is displaced
+  assert_eq!(pour(&mut Some(2), &mut tx, &mut spill), PourResult::Rejected);
+  assert_eq!(pour(&mut Some(3), &mut tx, &mut spill), PourResult::Rejected);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![0, 1]);
+  assert_eq!(pour(&mut Some(4), &mut tx, &mut spill), PourResult::Poured(2));
+  assert_eq!(spill.drain().collect::<Vec<i32>>(), vec![2, 3]);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![4]);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn pour_hook() {
+  use cb::Policy;
+  // overwrite once, then reject
+  let mut calls = 0;
+  let hook = Box::new(move |_unread| { calls += 1; calls == 1 });
+  let (mut tx, mut rx) = spsc::channel_with::<i32>(2, Policy::Hook(hook));
+  let mut spill = Spill::new(10);
+  assert_eq!(pour(&mut Some(0), &mut tx, &mut spill), PourResult::Poured(0));
+  assert_eq!(pour(&mut Some(1), &mut tx, &mut spill), PourResult::Poured(1));
+  assert_eq!(pour(&mut Some(2), &mut tx, &mut spill), PourResult::Overflowed(2));
+  assert_eq!(pour(&mut Some(3), &mut tx, &mut spill), PourResult::Rejected);
+  assert_eq!(spill.drain().collect::<Vec<i32>>(), vec![0, 3]);
+  assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1, 2]);
+  assert_eq!(pour(&mut Some(4), &mut tx, &mut spill), PourResult::Poured(3));
+  assert_eq!(tx.rejected(), 1);
+}
+
+#[test]
+fn fresh_items() {
+  use std::time::Duration;
+  let (mut tx, mut rx) = spsc::channel_timestamped::<i32>(4);
+  tx.put(|v| *v = Some(1));
+  tx.put(|v| *v = Some(2));
+  thread::sleep(Duration::from_millis(50));
+  tx.put(|v| *v = Some(3));
+  let mut i = rx.iter_fresh(Duration::from_millis(25));
+  assert_eq!(i.get_range(), (0, 3));
+  let (item, age) = i.next().unwrap();
+  assert_eq!(item, 3);
+  assert!(age < Duration::from_millis(25));
+  assert_eq!(i.next(), None);
+  assert_eq!(i.expired(), 2);
+  assert_eq!(rx.expired(), 2);
+  // expired items are consumed, they are not lost
+  assert_eq!(rx.iter().count(), 0);
+  assert_eq!(rx.dropped(), 0);
+}
+
+#[test]
+fn fresh_without_timestamps() {
+  use std::time::Duration;
+  let (mut tx, mut rx) = spsc::channel::<i32>(4);
+  tx.put(|v| *v = Some(1));
+  assert_eq!(rx.iter_fresh(Duration::ZERO).collect::<Vec<(i32, Duration)>>(), vec![(1, Duration::ZERO)]);
+  assert_eq!(rx.expired(), 0);
+}
@@ -0,0 +1,81 @@
+// the atomics of the ring protocol, built with --cfg loom the model
+// checker swaps in its own versions to explore the interleavings
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(loom))]
+pub use core::sync::atomic::{AtomicUsize, Ordering};
+
+// waiting for another thread that is about to finish, it may have been
+// preempted so give up the time slice where there is a scheduler
+#[cfg(loom)]
+pub use loom::thread::yield_now as pause;
+#[cfg(all(not(loom), feature = "std"))]
+pub use std::thread::yield_now as pause;
+#[cfg(all(not(loom), not(feature = "std")))]
+pub use core::hint::spin_loop as pause;
+
+// the elements go through with() and with_mut(), so loom also sees the
+// plain reads and writes and reports the ones that race
+#[cfg(loom)]
+pub use loom::cell::UnsafeCell;
+
+#[cfg(not(loom))]
+#[repr(transparent)]
+pub struct UnsafeCell<T> {
+  value: core::cell::UnsafeCell<T>,
+}
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+  #[inline(always)]
+  pub const fn new(value: T) -> UnsafeCell<T> {
+    UnsafeCell { value: core::cell::UnsafeCell::new(value) }
+  }
+
+  #[inline(always)]
+  pub fn with<F, R>(&self, f: F) -> R
+    where F : FnOnce(*const T) -> R
+  {
+    f(self.value.get())
+  }
+
+  #[inline(always)]
+  pub fn with_mut<F, R>(&self, f: F) -> R
+    where F : FnOnce(*mut T) -> R
+  {
+    f(self.value.get())
+  }
+}
+
+use core::ops::Deref;
+
+// keeps the value on its own cache line, so the writer's stores don't
+// invalidate the line the reader polls. 128 bytes covers the adjacent
+// line prefetch of recent x86 and the larger lines of some ARM cores.
+#[cfg_attr(feature = "cache-padded", repr(align(128)))]
+pub struct CachePadded<T> {
+  value: T,
+}
+
+impl<T> CachePadded<T> {
+  #[inline(always)]
+  pub const fn new(value: T) -> CachePadded<T> {
+    CachePadded { value, }
+  }
+}
+
+impl<T> From<T> for CachePadded<T> {
+  #[inline(always)]
+  fn from(value: T) -> CachePadded<T> {
+    CachePadded { value, }
+  }
+}
+
+impl<T> Deref for CachePadded<T> {
+  type Target = T;
+
+  #[inline(always)]
+  fn deref(&self) -> &T {
+    &self.value
+  }
+}